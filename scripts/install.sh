@@ -86,9 +86,9 @@ cat << EOF
     username: <学号>
     password: <密码>
 
-然后运行 xdwlan-login --oneshot 即可登录校园网。
+然后运行 xdwlan-login login 即可登录校园网。
 
-也可以不加 --oneshot 参数，让 xdwlan-login 以守护进程的方式运行，以实现自动登录和断网重连。
+也可以运行 xdwlan-login daemon，让 xdwlan-login 以守护进程的方式运行，以实现自动登录和断网重连。
 如果你想开机自动登录，可以开启 xdwlan-login 服务:
 
     sudo systemctl enable --now xdwlan-login@$(whoami).service
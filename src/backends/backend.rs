@@ -0,0 +1,92 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::config::{BackendKind, Config};
+use crate::portal::PortalDiscovery;
+use crate::utils::{format_bytes, format_duration};
+
+/// The current portal session of this device. Fields the portal does not report are `None`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub username: String,
+    pub ip: String,
+    pub mac: Option<String>,
+    /// Traffic used in the current billing cycle, in bytes.
+    pub bytes_used: Option<u64>,
+    /// Time online in the current billing cycle, in seconds.
+    pub seconds_online: Option<u64>,
+    /// Remaining balance of the account, in CNY.
+    pub balance: Option<f64>,
+}
+
+impl fmt::Display for SessionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Username:    {}", self.username)?;
+        write!(f, "IP:          {}", self.ip)?;
+        if let Some(mac) = &self.mac {
+            write!(f, "\nMAC:         {}", mac)?;
+        }
+        if let Some(bytes) = self.bytes_used {
+            write!(f, "\nTraffic:     {}", format_bytes(bytes))?;
+        }
+        if let Some(seconds) = self.seconds_online {
+            write!(f, "\nOnline time: {}", format_duration(seconds))?;
+        }
+        if let Some(balance) = self.balance {
+            write!(f, "\nBalance:     {:.2} CNY", balance)?;
+        }
+        Ok(())
+    }
+}
+
+/// A way to authenticate against the captive portal. `LoginTask` only decides *when* to login, the backend decides *how*.
+pub trait LoginBackend: Send + Sync {
+    /// A short name used in logs.
+    fn name(&self) -> &'static str;
+
+    /// Check whether our traffic is intercepted by the portal. Returns the login url if so.
+    fn detect_portal(&self) -> anyhow::Result<Option<String>>;
+
+    fn login(&self, username: &str, password: &str) -> anyhow::Result<()>;
+
+    /// Deregister this device from the portal.
+    fn logout(&self, _username: &str) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!(
+            "Logout is not supported by the {} backend.",
+            self.name()
+        ))
+    }
+
+    /// Query the portal for the session of this device. Returns `None` if there is no session.
+    fn query_session(&self) -> anyhow::Result<Option<SessionInfo>> {
+        Err(anyhow::anyhow!(
+            "Querying session is not supported by the {} backend.",
+            self.name()
+        ))
+    }
+}
+
+/// Create the backend selected in the config.
+pub fn create_backend(config: &Config) -> anyhow::Result<Box<dyn LoginBackend>> {
+    let discovery = PortalDiscovery::new(config.captive_portal_api.clone(), &config.portal);
+
+    let backend: Box<dyn LoginBackend> = match config.backend {
+        #[cfg(feature = "browser")]
+        BackendKind::Browser => {
+            Box::new(super::BrowserBackend::new(&config.portal, discovery).with_isp(config.isp))
+        }
+        #[cfg(not(feature = "browser"))]
+        BackendKind::Browser => {
+            return Err(anyhow::anyhow!(
+                "This build does not support the browser backend. Please set `backend: http` in the config."
+            ));
+        }
+        BackendKind::Http => Box::new(
+            super::SrunBackend::new(&config.portal, config.ac_id.clone(), discovery)?
+                .with_isp(config.isp),
+        ),
+    };
+
+    Ok(backend)
+}
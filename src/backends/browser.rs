@@ -0,0 +1,357 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use headless_chrome::{Browser, LaunchOptionsBuilder, Tab};
+use reqwest::Url;
+
+use super::{LoginBackend, SessionInfo, SrunBackend};
+use crate::config::Isp;
+use crate::error::{ErrorKind, LoginError};
+use crate::portal::PortalDiscovery;
+
+/// Fill in the form and click the login button. The credentials are passed as arguments rather than pasted into the source, so any character in a password is safe. The `input` events let the page's scripts notice the new values.
+///
+/// `domain` is the operator suffix, e.g. `@dx`, or empty for the campus network. It is picked in the domain dropdown if the dropdown offers it, otherwise appended to the username. The dropdown is left alone for the campus network.
+const LOGIN_SCRIPT: &str = r#"function login(username, password, domain) {
+    if (document.querySelector('div.control > button.btn-confirm')) {
+        document.querySelector('div.control > button.btn-confirm').click();
+    }
+    var fill = function (selector, value) {
+        var input = document.querySelector(selector);
+        input.value = value;
+        input.dispatchEvent(new Event('input', { bubbles: true }));
+        input.dispatchEvent(new Event('change', { bubbles: true }));
+    };
+    var dropdown = document.querySelector('#domain');
+    var offered = dropdown && dropdown.options && Array.prototype.some.call(dropdown.options, function (option) {
+        return option.value === domain;
+    });
+    if (domain && offered) {
+        fill('#domain', domain);
+    } else {
+        username += domain;
+    }
+    fill('#username', username);
+    fill('#password', password);
+    document.querySelector('#login-account').click();
+}"#;
+
+/// How long to wait for the portal to tell the result after clicking the login button.
+const RESULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Returns `success:` once the portal moves on to the success page, or `message:<text>` when it shows a dialog, e.g. `message:密码错误`.
+const RESULT_SCRIPT: &str = r#"(function () {
+    if (location.pathname.indexOf('success') !== -1) {
+        return 'success:';
+    }
+    var selectors = ['.component.dialog.active .section', '.dialog.active .content', '.layui-layer-content', '#message', '.error-msg'];
+    for (var i = 0; i < selectors.length; i++) {
+        var element = document.querySelector(selectors[i]);
+        if (element && element.offsetParent !== null && element.innerText.trim()) {
+            return 'message:' + element.innerText.trim();
+        }
+    }
+    return '';
+})()"#;
+
+/// Login by driving a Chromium-based browser through the login page, just like a user does.
+pub struct BrowserBackend {
+    /// When you are online, the portal shows the success page with a logout button.
+    portal: String,
+    discovery: PortalDiscovery,
+    isp: Isp,
+}
+
+impl BrowserBackend {
+    /// `portal` is the base URL of the portal, e.g. `https://w.xidian.edu.cn`.
+    pub fn new(portal: &str, discovery: PortalDiscovery) -> Self {
+        BrowserBackend {
+            portal: portal.to_string(),
+            discovery,
+            isp: Isp::default(),
+        }
+    }
+
+    /// `Isp::suffix` is picked in the domain dropdown, see `LOGIN_SCRIPT`.
+    pub fn with_isp(mut self, isp: Isp) -> Self {
+        self.isp = isp;
+        self
+    }
+
+    // In debug mode, we disable headless mode to see what's happening.
+    #[cfg(debug_assertions)]
+    fn create_browser(&self) -> anyhow::Result<Browser> {
+        let browser = Browser::new(LaunchOptionsBuilder::default().headless(false).build()?)?;
+
+        Ok(browser)
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn create_browser(&self) -> anyhow::Result<Browser> {
+        use std::env::temp_dir;
+
+        let user_data_dir = temp_dir().join("xdwlan-login");
+
+        if !user_data_dir.exists() {
+            std::fs::create_dir(&user_data_dir)?;
+            log::info!("User data dir: {}", user_data_dir.display());
+        }
+
+        let browser = Browser::new(
+            LaunchOptionsBuilder::default()
+                .user_data_dir(Some(user_data_dir))
+                .build()?,
+        )?;
+
+        Ok(browser)
+    }
+
+    /// The browser that will be launched, if any can be found.
+    pub fn executable() -> Option<std::path::PathBuf> {
+        headless_chrome::browser::default_executable().ok()
+    }
+
+    /// Wait for the portal to show the login result, and turn an error dialog into a typed error.
+    fn wait_for_result(&self, tab: &Tab) -> anyhow::Result<()> {
+        let deadline = Instant::now() + RESULT_TIMEOUT;
+        while Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(500));
+
+            // The page may be navigating, so errors here are expected.
+            let result = match tab.evaluate(RESULT_SCRIPT, false) {
+                Ok(object) => object.value,
+                Err(e) => {
+                    log::debug!("Failed to read the login result: {}", e);
+                    continue;
+                }
+            };
+            let result = result
+                .as_ref()
+                .and_then(|value| value.as_str())
+                .unwrap_or_default();
+
+            if result.starts_with("success:") {
+                return Ok(());
+            }
+            if let Some(message) = result.strip_prefix("message:") {
+                log::info!("The portal says: {}", message);
+                if message.contains("成功") || message.to_lowercase().contains("success") {
+                    return Ok(());
+                }
+                return Err(LoginError::from_message(message).into());
+            }
+        }
+
+        // No news is not necessarily bad news, the connectivity check after login will tell.
+        log::debug!("The portal did not show the login result in time.");
+        Ok(())
+    }
+}
+
+impl LoginBackend for BrowserBackend {
+    fn name(&self) -> &'static str {
+        "browser"
+    }
+
+    fn detect_portal(&self) -> anyhow::Result<Option<String>> {
+        self.discovery.find_login_url()
+    }
+
+    /// Open a browser and login to the network.
+    fn login(&self, username: &str, password: &str) -> anyhow::Result<()> {
+        let url = self
+            .detect_portal()?
+            .ok_or_else(|| LoginError::new(ErrorKind::PortalUnreachable, "Login url not found."))?;
+        log::info!("Got login url: {}", url);
+
+        // Create a browser and a new tab.
+        let browser = self.create_browser().map_err(|e| {
+            LoginError::new(
+                ErrorKind::BrowserFailure,
+                format!("Failed to launch browser: {}", e),
+            )
+        })?;
+        let tab = browser.new_tab()?;
+
+        // Navigate to the login page. Try at most 5 times.
+        for i in 0..5 {
+            match tab.navigate_to(&url) {
+                Ok(_) => {
+                    break;
+                }
+                Err(e) => {
+                    log::debug!("Navigate Error: {}", e);
+
+                    if i == 4 {
+                        return Err(LoginError::new(
+                            ErrorKind::PortalUnreachable,
+                            "Navigate failed for 5 times.",
+                        )
+                        .into());
+                    }
+                }
+            }
+        }
+        tab.wait_until_navigated()?;
+
+        // We check the host of the page to determine whether we are redirected to the login page.
+        let login_host = host(&url);
+        let url = tab.get_url();
+        let page_host = host(&url);
+        if page_host.is_some() && (page_host == login_host || page_host == host(&self.portal)) {
+            log::debug!("You are redirected to the login page {}", url);
+
+            // Sometimes, the page will show a dialog says "Net Error".
+            // Actually I don't know why, just reload the page to avoid it.
+            // Page has to be reload with cache, otherwise it will always complain "Net Error".
+            tab.reload(false, None)?;
+            tab.wait_until_navigated()?;
+
+            // We try to login here.
+            log::info!("Try to login...");
+            let body = tab.wait_for_element("body")?;
+            body.call_js_fn(
+                LOGIN_SCRIPT,
+                login_args(username, password, self.isp.suffix()),
+                false,
+            )?;
+
+            self.wait_for_result(&tab)?;
+        } else {
+            return Err(LoginError::new(
+                ErrorKind::PortalUnreachable,
+                format!("Unknown login url: {}", url),
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// The success page reads the session from the portal API, so we do the same without launching a browser.
+    fn query_session(&self) -> anyhow::Result<Option<SessionInfo>> {
+        SrunBackend::new(&self.portal, None, self.discovery.clone())?.query_session()
+    }
+
+    /// Open the success page and click the logout button.
+    fn logout(&self, _username: &str) -> anyhow::Result<()> {
+        let browser = self.create_browser()?;
+        let tab = browser.new_tab()?;
+        tab.navigate_to(&self.portal)?;
+        tab.wait_until_navigated()?;
+
+        log::info!("Try to logout...");
+        tab.wait_for_element("#logout")?.click()?;
+
+        // The portal asks for confirmation before logging out.
+        if let Ok(confirm) = tab.wait_for_element("div.control > button.btn-confirm") {
+            confirm.click()?;
+        }
+
+        // The portal goes back to the login page once the session is gone.
+        tab.wait_for_element("#login-account").map_err(|_| {
+            anyhow::anyhow!("Logout failed: the portal did not return to the login page.")
+        })?;
+
+        Ok(())
+    }
+}
+
+/// The arguments of `LOGIN_SCRIPT`. They are sent to the browser as JSON values, so they reach the page exactly as given.
+fn login_args(username: &str, password: &str, domain: &str) -> Vec<serde_json::Value> {
+    vec![
+        serde_json::Value::from(username),
+        serde_json::Value::from(password),
+        serde_json::Value::from(domain),
+    ]
+}
+
+fn host(url: &str) -> Option<String> {
+    Url::parse(url).ok()?.host_str().map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headless_browser() -> Browser {
+        Browser::new(
+            LaunchOptionsBuilder::default()
+                .headless(true)
+                .build()
+                .unwrap(),
+        )
+        .unwrap()
+    }
+
+    /// Run `LOGIN_SCRIPT` on `page` and read back the username, password and domain it filled in.
+    fn fill_login_form(
+        browser: &Browser,
+        page: &str,
+        username: &str,
+        password: &str,
+        domain: &str,
+    ) -> [String; 3] {
+        let tab = browser.new_tab().unwrap();
+        tab.navigate_to(&format!("data:text/html;charset=utf-8,{}", page))
+            .unwrap()
+            .wait_until_navigated()
+            .unwrap();
+
+        let body = tab.wait_for_element("body").unwrap();
+        body.call_js_fn(LOGIN_SCRIPT, login_args(username, password, domain), false)
+            .unwrap();
+
+        ["#username", "#password", "#domain"].map(|selector| {
+            let script = format!(
+                "(document.querySelector('{}') || {{ value: '' }}).value",
+                selector
+            );
+            let value = tab.evaluate(&script, false).unwrap().value.unwrap();
+            value.as_str().unwrap().to_string()
+        })
+    }
+
+    #[test]
+    #[ignore = "needs a Chromium-based browser"]
+    fn login_script_keeps_awkward_characters() {
+        let browser = headless_browser();
+        let page = r#"<input id="username"><input id="password" type="password"><button id="login-account"></button>"#;
+        let passwords = [
+            "it's",
+            r"back\slash\",
+            r#"say "hi""#,
+            "</script><script>alert(1)</script>",
+            "'); alert(1); ('",
+            "${x}`tick`",
+            "密码🔑",
+        ];
+
+        for password in passwords {
+            let [username, filled, _] = fill_login_form(&browser, page, "230000", password, "");
+            assert_eq!(username, "230000");
+            assert_eq!(filled, password);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a Chromium-based browser"]
+    fn login_script_picks_offered_domains_only() {
+        let browser = headless_browser();
+        // The page remembers the last choice, here `@dx`.
+        let page = r#"<input id="username"><input id="password" type="password"><select id="domain"><option value="">校园网</option><option value="@dx" selected>电信</option><option value="@yd">移动</option></select><button id="login-account"></button>"#;
+
+        let [username, _, domain] = fill_login_form(&browser, page, "230000", "x", "@yd");
+        assert_eq!([username.as_str(), domain.as_str()], ["230000", "@yd"]);
+
+        let [username, _, domain] = fill_login_form(&browser, page, "230000", "x", "@lt");
+        assert_eq!([username.as_str(), domain.as_str()], ["230000@lt", "@dx"]);
+
+        let [username, _, domain] = fill_login_form(&browser, page, "230000", "x", "");
+        assert_eq!([username.as_str(), domain.as_str()], ["230000", "@dx"]);
+
+        let page = r#"<input id="username"><input id="password" type="password"><button id="login-account"></button>"#;
+        let [username, _, _] = fill_login_form(&browser, page, "230000", "x", "@dx");
+        assert_eq!(username, "230000@dx");
+    }
+}
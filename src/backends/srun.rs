@@ -0,0 +1,552 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use super::{LoginBackend, SessionInfo};
+use crate::config::Isp;
+use crate::error::LoginError;
+use crate::portal::PortalDiscovery;
+
+/// The portal page wraps every API response in a jQuery JSONP callback, and some deployments refuse requests without one.
+const CALLBACK: &str = "jQuery112406118340540763985_1556004912581";
+const ENC_VER: &str = "srun_bx1";
+const N: &str = "200";
+const TYPE: &str = "1";
+const BASE64_ALPHABET: &[u8; 64] =
+    b"LVoJPiCN2R8G90yg+hmFHuacZ1OWMnrsSTXkYpUq/3dlbfKwv6xztjI7DeBE45QA";
+
+#[cfg(target_os = "windows")]
+const OS: (&str, &str) = ("Windows 10", "Windows");
+#[cfg(not(target_os = "windows"))]
+const OS: (&str, &str) = ("Linux", "Linux");
+
+#[derive(Deserialize)]
+struct ChallengeResponse {
+    challenge: String,
+    client_ip: String,
+}
+
+#[derive(Deserialize)]
+struct PortalResponse {
+    #[serde(default)]
+    error: String,
+    #[serde(default)]
+    error_msg: String,
+}
+
+/// Numbers in `rad_user_info` come as either JSON numbers or strings depending on the portal version, so we keep them loose.
+#[derive(Deserialize)]
+struct UserInfoResponse {
+    #[serde(default)]
+    error: String,
+    #[serde(default)]
+    user_name: String,
+    #[serde(default)]
+    online_ip: String,
+    user_mac: Option<String>,
+    sum_bytes: Option<serde_json::Value>,
+    sum_seconds: Option<serde_json::Value>,
+    user_balance: Option<serde_json::Value>,
+}
+
+/// The `info` field of a login request, serialized in the same field order as the portal's JavaScript.
+#[derive(Serialize)]
+struct LoginInfo<'a> {
+    username: &'a str,
+    password: &'a str,
+    ip: &'a str,
+    acid: &'a str,
+    enc_ver: &'a str,
+}
+
+/// A client for the Srun portal API (`/cgi-bin/get_challenge`, `/cgi-bin/srun_portal`). It performs the same handshake as the login page does in a browser, so no browser is needed.
+pub struct SrunBackend {
+    portal: String,
+    ac_id: Option<String>,
+    discovery: PortalDiscovery,
+    isp: Isp,
+    client: reqwest::blocking::Client,
+}
+
+impl SrunBackend {
+    /// `portal` is the base URL of the portal, e.g. `https://w.xidian.edu.cn`. It can point to a local mock portal for testing. If `ac_id` is `None`, it is discovered from the portal's redirection.
+    pub fn new(
+        portal: &str,
+        ac_id: Option<String>,
+        discovery: PortalDiscovery,
+    ) -> anyhow::Result<Self> {
+        let client = reqwest::blocking::ClientBuilder::new().no_proxy().build()?;
+
+        Ok(SrunBackend {
+            portal: portal.trim_end_matches('/').to_string(),
+            ac_id,
+            discovery,
+            isp: Isp::default(),
+            client,
+        })
+    }
+
+    /// The username is sent with `Isp::suffix` appended.
+    pub fn with_isp(mut self, isp: Isp) -> Self {
+        self.isp = isp;
+        self
+    }
+
+    /// Get the access controller id. The portal redirects `/` to `/srun_portal_pc?ac_id=N`, so we read it from there.
+    fn get_ac_id(&self) -> anyhow::Result<String> {
+        if let Some(ac_id) = &self.ac_id {
+            return Ok(ac_id.clone());
+        }
+
+        let resp = self.client.get(&self.portal).send()?;
+        if let Some((_, ac_id)) = resp.url().query_pairs().find(|(k, _)| k == "ac_id") {
+            return Ok(ac_id.into_owned());
+        }
+
+        let content = resp.text()?;
+        let re = regex::Regex::new(r#"ac_id=(?P<id>\d+)|id="ac_id"\s+value="(?P<value>\d+)""#)?;
+        if let Some(cap) = re.captures(&content) {
+            let id = cap.name("id").or(cap.name("value")).unwrap();
+            return Ok(id.as_str().to_string());
+        }
+
+        Err(anyhow::anyhow!("ac_id not found on {}.", self.portal))
+    }
+
+    /// Send a JSONP request to the portal and decode the payload.
+    fn get_jsonp<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        params: &[(&str, &str)],
+    ) -> anyhow::Result<T> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .as_millis()
+            .to_string();
+
+        let resp = self
+            .client
+            .get(format!("{}{}", self.portal, path))
+            .query(&[("callback", CALLBACK)])
+            .query(params)
+            .query(&[("_", timestamp.as_str())])
+            .send()?
+            .error_for_status()?;
+        let content = resp.text()?;
+        log::debug!("{} responded: {}", path, content);
+
+        Ok(serde_json::from_str(strip_jsonp(&content))?)
+    }
+
+    /// The challenge is both the token for encryption and the way to learn our IP address seen by the portal.
+    fn get_challenge(&self, username: &str) -> anyhow::Result<ChallengeResponse> {
+        self.get_jsonp(
+            "/cgi-bin/get_challenge",
+            &[("username", username), ("ip", "")],
+        )
+    }
+}
+
+impl LoginBackend for SrunBackend {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    fn login(&self, username: &str, password: &str) -> anyhow::Result<()> {
+        log::info!("Try to login via {}...", self.portal);
+
+        let username = &format!("{}{}", username, self.isp.suffix());
+        let ac_id = self.get_ac_id()?;
+
+        let challenge = self.get_challenge(username)?;
+        let token = challenge.challenge;
+        let ip = challenge.client_ip;
+        log::debug!("Got challenge for ip {}", ip);
+
+        let info = serde_json::to_string(&LoginInfo {
+            username,
+            password,
+            ip: &ip,
+            acid: &ac_id,
+            enc_ver: ENC_VER,
+        })?;
+        let info = format!(
+            "{{SRBX1}}{}",
+            base64_encode(&xencode(info.as_bytes(), token.as_bytes()))
+        );
+        let hmd5 = hmac_md5(token.as_bytes(), password.as_bytes());
+        let chksum = chksum(&token, username, &hmd5, &ac_id, &ip, &info);
+        let password = format!("{{MD5}}{}", hmd5);
+
+        let resp: PortalResponse = self.get_jsonp(
+            "/cgi-bin/srun_portal",
+            &[
+                ("action", "login"),
+                ("username", username),
+                ("password", &password),
+                ("os", OS.0),
+                ("name", OS.1),
+                ("double_stack", "0"),
+                ("chksum", &chksum),
+                ("info", &info),
+                ("ac_id", &ac_id),
+                ("ip", &ip),
+                ("n", N),
+                ("type", TYPE),
+            ],
+        )?;
+
+        match resp.error.as_str() {
+            "ok" | "ip_already_online_error" => Ok(()),
+            _ => Err(LoginError::from_message(format!(
+                "Portal rejected login: {} {}",
+                resp.error, resp.error_msg
+            ))
+            .into()),
+        }
+    }
+
+    /// Besides looking for the redirection, the portal API answering us also means we are behind the portal.
+    fn detect_portal(&self) -> anyhow::Result<Option<String>> {
+        if let Ok(Some(url)) = self.discovery.find_login_url() {
+            return Ok(Some(url));
+        }
+
+        match self.get_jsonp::<UserInfoResponse>("/cgi-bin/rad_user_info", &[]) {
+            Ok(resp) if resp.error == "not_online_error" => Ok(Some(self.portal.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    fn query_session(&self) -> anyhow::Result<Option<SessionInfo>> {
+        let resp: UserInfoResponse = self.get_jsonp("/cgi-bin/rad_user_info", &[])?;
+
+        match resp.error.as_str() {
+            "ok" => Ok(Some(SessionInfo {
+                username: resp.user_name,
+                ip: resp.online_ip,
+                mac: resp.user_mac.filter(|mac| !mac.is_empty()),
+                bytes_used: resp.sum_bytes.as_ref().and_then(as_f64).map(|v| v as u64),
+                seconds_online: resp.sum_seconds.as_ref().and_then(as_f64).map(|v| v as u64),
+                balance: resp.user_balance.as_ref().and_then(as_f64),
+            })),
+            "not_online_error" => Ok(None),
+            _ => Err(anyhow::anyhow!("Failed to query session: {}", resp.error)),
+        }
+    }
+
+    fn logout(&self, username: &str) -> anyhow::Result<()> {
+        log::info!("Try to logout via {}...", self.portal);
+
+        let username = &format!("{}{}", username, self.isp.suffix());
+        let ac_id = self.get_ac_id()?;
+        let ip = self.get_challenge(username)?.client_ip;
+
+        let resp: PortalResponse = self.get_jsonp(
+            "/cgi-bin/srun_portal",
+            &[
+                ("action", "logout"),
+                ("username", username),
+                ("ac_id", &ac_id),
+                ("ip", &ip),
+            ],
+        )?;
+
+        match resp.error.as_str() {
+            "ok" => Ok(()),
+            _ => Err(anyhow::anyhow!(
+                "Portal rejected logout: {} {}",
+                resp.error,
+                resp.error_msg
+            )),
+        }
+    }
+}
+
+fn as_f64(value: &serde_json::Value) -> Option<f64> {
+    match value {
+        serde_json::Value::Number(n) => n.as_f64(),
+        serde_json::Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Strip `callback(...)` around a JSONP payload. Plain JSON is returned as is.
+fn strip_jsonp(content: &str) -> &str {
+    let content = content.trim();
+    match (content.find('('), content.rfind(')')) {
+        (Some(start), Some(end)) if !content.starts_with('{') && start < end => {
+            &content[start + 1..end]
+        }
+        _ => content,
+    }
+}
+
+/// Hex encoded HMAC-MD5 of `msg`, which the portal's `md5(password, token)` computes.
+fn hmac_md5(key: &[u8], msg: &[u8]) -> String {
+    // HMAC accepts keys of any length.
+    let mut mac = Hmac::<Md5>::new_from_slice(key).unwrap();
+    mac.update(msg);
+    to_hex(&mac.finalize().into_bytes())
+}
+
+/// The checksum of a login request: SHA-1 of the fields, each preceded by the token.
+fn chksum(token: &str, username: &str, hmd5: &str, ac_id: &str, ip: &str, info: &str) -> String {
+    let fields = ["", username, hmd5, ac_id, ip, N, TYPE, info].join(token);
+    to_hex(&Sha1::digest(fields.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Pack bytes into little-endian words, optionally appending the original length.
+fn to_words(bytes: &[u8], with_length: bool) -> Vec<u32> {
+    let mut words: Vec<u32> = bytes
+        .chunks(4)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0, |word, (i, &b)| word | (b as u32) << (8 * i))
+        })
+        .collect();
+    if with_length {
+        words.push(bytes.len() as u32);
+    }
+    words
+}
+
+/// The XXTEA-like cipher from the portal's `xEncode`.
+fn xencode(msg: &[u8], key: &[u8]) -> Vec<u8> {
+    if msg.is_empty() {
+        return vec![];
+    }
+
+    let mut v = to_words(msg, true);
+    let mut k = to_words(key, false);
+    if k.len() < 4 {
+        k.resize(4, 0);
+    }
+
+    let n = v.len() - 1;
+    let mut z = v[n];
+    let mut y;
+    let c: u32 = 0x9E3779B9;
+    let mut d: u32 = 0;
+    let mut q = 6 + 52 / (n + 1);
+
+    let mix = |z: u32, y: u32, d: u32, p: usize, e: usize| -> u32 {
+        let m = (z >> 5 ^ y << 2).wrapping_add((y >> 3 ^ z << 4) ^ (d ^ y));
+        m.wrapping_add(k[(p & 3) ^ e] ^ z)
+    };
+
+    while q > 0 {
+        q -= 1;
+        d = d.wrapping_add(c);
+        let e = (d >> 2 & 3) as usize;
+        for p in 0..n {
+            y = v[p + 1];
+            v[p] = v[p].wrapping_add(mix(z, y, d, p, e));
+            z = v[p];
+        }
+        y = v[0];
+        v[n] = v[n].wrapping_add(mix(z, y, d, n, e));
+        z = v[n];
+    }
+
+    v.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+/// Base64 with the portal's shuffled alphabet.
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let triple = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(triple >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use reqwest::Url;
+    use serde_json::json;
+
+    use super::*;
+    use crate::error::{classify, ErrorKind};
+    use crate::utils::{http_response, serve_http};
+
+    const TOKEN: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+    const INFO: &str =
+        r#"{"username":"230000","password":"p'w","ip":"10.0.0.2","acid":"1","enc_ver":"srun_bx1"}"#;
+    /// `xEncode` and `base64` of `INFO` by the portal's JavaScript.
+    const ENCODED_INFO: &str = "V7w0SZAokVmy5u9ZVPMEUjNHEbgx8jRIXZH28RwKn5Faw1mpxAB1hdvNkyl+QOir5QfEI0Yjr+N2ZAR/ECvpfSB8aQ+M8eKnWKcEx2Hxdtyx2QpEstDAXyNPnHM=";
+
+    #[test]
+    fn xencode_matches_portal() {
+        assert_eq!(
+            base64_encode(&xencode(INFO.as_bytes(), TOKEN.as_bytes())),
+            ENCODED_INFO
+        );
+        // Keys shorter than 4 words are padded with zeros.
+        assert_eq!(
+            base64_encode(&xencode(b"hello", b"key")),
+            "42TNzCArzIwOP3oW"
+        );
+        assert!(xencode(b"", b"key").is_empty());
+    }
+
+    #[test]
+    fn base64_pads_like_portal() {
+        assert_eq!(base64_encode(b"abc"), "ZaRk");
+        assert_eq!(base64_encode(b"ab"), "Za2=");
+        assert_eq!(base64_encode(b"a"), "Z+==");
+        assert_eq!(base64_encode(b""), "");
+    }
+
+    #[test]
+    fn hmac_md5_matches_portal() {
+        assert_eq!(
+            hmac_md5(TOKEN.as_bytes(), b"p'w"),
+            "02bdf6550e0c966675a8bc79978f9a08"
+        );
+    }
+
+    #[test]
+    fn chksum_matches_portal() {
+        let info = format!("{{SRBX1}}{}", ENCODED_INFO);
+        assert_eq!(
+            chksum(
+                TOKEN,
+                "230000",
+                "02bdf6550e0c966675a8bc79978f9a08",
+                "1",
+                "10.0.0.2",
+                &info
+            ),
+            "09dce90d48caa475aeb23eb7138e8994a7d268e9"
+        );
+    }
+
+    #[test]
+    fn strip_jsonp_cases() {
+        assert_eq!(
+            strip_jsonp(r#"jQuery123({"error":"ok"})"#),
+            r#"{"error":"ok"}"#
+        );
+        assert_eq!(strip_jsonp(" cb({\"a\":1});\n"), r#"{"a":1}"#);
+        // Plain JSON, even with parentheses in a value.
+        assert_eq!(
+            strip_jsonp(r#"{"error_msg":"E2553 (password)"}"#),
+            r#"{"error_msg":"E2553 (password)"}"#
+        );
+        assert_eq!(strip_jsonp("not jsonp"), "not jsonp");
+    }
+
+    /// Serve `get_challenge`, `srun_portal` and `rad_user_info` on a random port, like the portal does for one device. Returns the base URL.
+    fn mock_portal() -> String {
+        let port = serve_http(|_| {
+            let mut online = false;
+            move |target: &str| {
+                let url = Url::parse(&format!("http://mock{}", target)).unwrap();
+                let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+                let payload = respond(url.path(), &params, &mut online);
+
+                let body = format!("{}({})", params["callback"], payload);
+                http_response("200 OK", "text/javascript", &body)
+            }
+        });
+
+        format!("http://127.0.0.1:{}", port)
+    }
+
+    fn respond(
+        path: &str,
+        params: &HashMap<String, String>,
+        online: &mut bool,
+    ) -> serde_json::Value {
+        match (path, params.get("action").map(String::as_str)) {
+            ("/cgi-bin/get_challenge", _) => {
+                json!({"error": "ok", "challenge": TOKEN, "client_ip": "10.0.0.2"})
+            }
+            ("/cgi-bin/srun_portal", Some("login")) => {
+                let hmd5 = hmac_md5(TOKEN.as_bytes(), b"p'w");
+                let expected = chksum(
+                    TOKEN,
+                    &params["username"],
+                    &hmd5,
+                    &params["ac_id"],
+                    &params["ip"],
+                    &params["info"],
+                );
+                if params["password"] != format!("{{MD5}}{}", hmd5) {
+                    return json!({"error": "login_error", "error_msg": "E2553: Password is error."});
+                }
+                assert_eq!(params["chksum"], expected);
+                assert!(params["info"].starts_with("{SRBX1}"));
+                *online = true;
+                json!({"error": "ok", "error_msg": ""})
+            }
+            ("/cgi-bin/srun_portal", Some("logout")) => {
+                *online = false;
+                json!({"error": "ok"})
+            }
+            ("/cgi-bin/rad_user_info", _) if *online => json!({
+                "error": "ok",
+                "user_name": "230000",
+                "online_ip": "10.0.0.2",
+                "user_mac": "",
+                "sum_bytes": "1234",
+                "sum_seconds": 60,
+                "user_balance": 12.5,
+            }),
+            ("/cgi-bin/rad_user_info", _) => json!({"error": "not_online_error"}),
+            _ => json!({"error": "unknown"}),
+        }
+    }
+
+    #[test]
+    fn login_logout_against_mock_portal() {
+        let backend = SrunBackend::new(
+            &mock_portal(),
+            Some("1".to_string()),
+            PortalDiscovery::default(),
+        )
+        .unwrap();
+
+        assert!(backend.query_session().unwrap().is_none());
+
+        let e = backend.login("230000", "wrong").unwrap_err();
+        assert_eq!(classify(&e), ErrorKind::WrongCredentials);
+
+        backend.login("230000", "p'w").unwrap();
+        let session = backend.query_session().unwrap().unwrap();
+        assert_eq!(session.username, "230000");
+        assert_eq!(session.ip, "10.0.0.2");
+        assert_eq!(session.mac, None);
+        assert_eq!(session.bytes_used, Some(1234));
+        assert_eq!(session.seconds_online, Some(60));
+        assert_eq!(session.balance, Some(12.5));
+
+        backend.logout("230000").unwrap();
+        assert!(backend.query_session().unwrap().is_none());
+    }
+}
@@ -0,0 +1,880 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use xdwlan_login::backends::{create_backend, SessionInfo};
+use xdwlan_login::config::{
+    config_path, load_config, search_paths, Config, Overrides, CONFIG_TEMPLATE,
+};
+use xdwlan_login::control::{self, ControlServer, Request, Response};
+use xdwlan_login::error::{classify, ErrorKind, LoginError};
+use xdwlan_login::logger::REDACTED;
+use xdwlan_login::portal::{CaptivePortalState, PortalDiscovery};
+use xdwlan_login::probe::NetState;
+use xdwlan_login::secret::Secret;
+use xdwlan_login::tasks::{AppEvent, Health, LoginTask, Supervisor, Task, TaskStatus};
+
+use crate::Exit;
+
+/// Lets other programs consume the result instead of parsing logs.
+fn output_arg() -> clap::Arg {
+    clap::Arg::new("output")
+        .long("output")
+        .value_name("FORMAT")
+        .help("Print the result as text or as a JSON object.")
+        .value_parser(["text", "json"])
+        .default_value("text")
+}
+
+/// Shared by `login` and the legacy `--oneshot`.
+fn max_attempts_arg() -> clap::Arg {
+    clap::Arg::new("max-attempts")
+        .long("max-attempts")
+        .value_name("N")
+        .help("Give up after N attempts.")
+        .value_parser(clap::value_parser!(u32).range(1..))
+}
+
+fn timeout_arg() -> clap::Arg {
+    clap::Arg::new("timeout")
+        .long("timeout")
+        .value_name("SECONDS")
+        .help("Give up after SECONDS seconds. This is best-effort: a login attempt already running is not interrupted.")
+        .value_parser(clap::value_parser!(u64))
+}
+
+/// The limits given by `max_attempts_arg` and `timeout_arg`.
+fn login_limits(args: &clap::ArgMatches) -> (Option<u32>, Option<Duration>) {
+    let max_attempts = args.get_one::<u32>("max-attempts").copied();
+    let timeout = args
+        .get_one::<u64>("timeout")
+        .map(|t| Duration::from_secs(*t));
+    (max_attempts, timeout)
+}
+
+fn command() -> clap::Command {
+    clap::Command::new("xdwlan-login")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Login to Xidian University's wireless network.")
+        .arg(
+            clap::Arg::new("config")
+                .short('c')
+                .long("config")
+                .value_name("PATH")
+                .help("Read the config from PATH instead of searching for it. Overrides $XDWLAN_LOGIN_CONFIG.")
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true),
+        )
+        .arg(
+            clap::Arg::new("username")
+                .long("username")
+                .value_name("NAME")
+                .help("Login as NAME. Overrides $XDWLAN_USERNAME and the config file.")
+                .global(true),
+        )
+        .arg(
+            clap::Arg::new("password-stdin")
+                .long("password-stdin")
+                .help("Read the password from the first line of stdin. Overrides $XDWLAN_PASSWORD and the config file.")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            // Kept for scripts written before the `login` subcommand.
+            clap::Arg::new("oneshot")
+                .short('o')
+                .long("oneshot")
+                .help("Same as `login`.")
+                .hide(true)
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(max_attempts_arg().requires("oneshot").hide(true))
+        .arg(timeout_arg().requires("oneshot").hide(true))
+        .after_help(
+            "Exit codes: 0 success, 1 other errors, 3 already online, 4 credentials rejected, \
+             5 account in arrears or disabled, 6 portal not found, 7 timed out, 8 browser unavailable, \
+             9 behind the captive portal, 10 no network, 11 out of login attempts.",
+        )
+        .subcommand(
+            clap::Command::new("daemon")
+                .about("Keep the device online, login again whenever the network drops. This is the default."),
+        )
+        .subcommand(
+            clap::Command::new("login")
+                .about("Login once and quit.")
+                .arg(
+                    clap::Arg::new("now")
+                        .long("now")
+                        .help("Ask the running daemon to login right away instead.")
+                        .action(clap::ArgAction::SetTrue)
+                        .conflicts_with_all(["max-attempts", "timeout", "output"]),
+                )
+                .arg(max_attempts_arg())
+                .arg(timeout_arg())
+                .arg(output_arg()),
+        )
+        .subcommand(
+            clap::Command::new("logout")
+                .about("Logout this device from the portal and quit. A running daemon is paused as well."),
+        )
+        .subcommand(
+            clap::Command::new("status")
+                .about("Show the portal session of this device: traffic, online time, balance.")
+                .arg(output_arg()),
+        )
+        .subcommand(
+            clap::Command::new("check")
+                .about("Check whether the device is online, behind the captive portal, or has no network.")
+                .arg(output_arg()),
+        )
+        .subcommand(
+            clap::Command::new("pause").about("Ask the running daemon to stop logging in."),
+        )
+        .subcommand(clap::Command::new("resume").about("Ask the running daemon to login again."))
+        .subcommand(
+            clap::Command::new("reload").about("Ask the running daemon to load the config again."),
+        )
+        .subcommand(
+            clap::Command::new("config")
+                .about("Manage the config file.")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("show")
+                        .about("Print the config as it is loaded, with overrides applied.")
+                        .arg(
+                            clap::Arg::new("redacted")
+                                .long("redacted")
+                                .help("Hide the password.")
+                                .action(clap::ArgAction::SetTrue),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("validate")
+                        .about("Check that the config can be loaded and the backend can be created."),
+                )
+                .subcommand(
+                    clap::Command::new("init")
+                        .about("Write a config template to the default location.")
+                        .arg(
+                            clap::Arg::new("force")
+                                .long("force")
+                                .help("Overwrite an existing config file.")
+                                .action(clap::ArgAction::SetTrue),
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("diagnose")
+                .about("Print what the program sees, to help with bug reports."),
+        )
+}
+
+/// On Linux, the program runs in CLI mode. The login task runs in the thread (in daemon mode) for checking network connectivity and logging in. The main thread is responsible for handling signals and quitting the login task.
+pub fn run() -> anyhow::Result<Exit> {
+    let args = command().get_matches();
+    let source = ConfigSource::from_args(&args)?;
+
+    // These commands have to work without a valid config.
+    match args.subcommand() {
+        Some(("config", args)) => match args.subcommand() {
+            Some(("show", args)) => return config_show(&source, args.get_flag("redacted")),
+            Some(("validate", _)) => return config_validate(&source),
+            Some(("init", args)) => return config_init(&source.path, args.get_flag("force")),
+            _ => unreachable!("clap requires a config subcommand"),
+        },
+        Some(("diagnose", _)) => return diagnose(&source),
+        // These commands are for the running daemon.
+        Some(("login", args)) if args.get_flag("now") => return forward(Request::Login),
+        Some(("pause", _)) => return forward(Request::Pause),
+        Some(("resume", _)) => return forward(Request::Resume),
+        Some(("reload", _)) => return forward(Request::Reload),
+        _ => {}
+    }
+
+    log::info!("Start.");
+
+    let (name, sub_args) = match args.subcommand() {
+        Some((name, sub_args)) => (name, Some(sub_args)),
+        None => ("daemon", None),
+    };
+    let json = sub_args
+        .and_then(|sub_args| sub_args.try_get_one::<String>("output").ok().flatten())
+        .is_some_and(|output| output == "json");
+
+    let mut report = Report::new(name);
+    let result = execute(&args, &source, &mut report);
+
+    if json {
+        report.finish(&result);
+        println!("{}", serde_json::to_string(&report)?);
+    } else if result.is_ok() {
+        report.print();
+    }
+
+    result
+}
+
+/// Where the config comes from: the config file, and the credentials given by flags or environment variables.
+struct ConfigSource {
+    path: PathBuf,
+    overrides: Overrides,
+}
+
+impl ConfigSource {
+    /// Flags take precedence over environment variables, which take precedence over the config file.
+    fn from_args(args: &clap::ArgMatches) -> anyhow::Result<Self> {
+        let path = config_path(args.get_one::<PathBuf>("config").map(PathBuf::as_path));
+
+        let password = if args.get_flag("password-stdin") {
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line)?;
+            Some(Secret::new(line.trim_end_matches(['\r', '\n'])))
+        } else {
+            None
+        };
+        let flags = Overrides {
+            username: args.get_one::<String>("username").cloned(),
+            password,
+        };
+
+        Ok(ConfigSource {
+            path,
+            overrides: flags.or(Overrides::from_env()),
+        })
+    }
+
+    fn load(&self) -> anyhow::Result<Config> {
+        load_config(&self.path, &self.overrides)
+    }
+}
+
+fn execute(
+    args: &clap::ArgMatches,
+    source: &ConfigSource,
+    report: &mut Report,
+) -> anyhow::Result<Exit> {
+    match args.subcommand() {
+        // Let the daemon logout, so that it does not login again right after.
+        Some(("logout", _)) => {
+            if let Ok(response) = control::send(Request::Logout) {
+                return reply(response);
+            }
+        }
+        Some(("status", _)) => {
+            if let Ok(response) = control::send(Request::Status) {
+                report.daemon = response.status;
+                report.health = response.health;
+            }
+        }
+        _ => {}
+    }
+
+    let loaded = source.load().and_then(|config| {
+        let discovery = PortalDiscovery::new(config.captive_portal_api.clone(), &config.portal);
+        Ok((discovery, LoginTask::new(config)?))
+    });
+    let (discovery, login_task) = match loaded {
+        Ok(loaded) => loaded,
+        // The daemon may run with a config this user can not read, e.g. as the systemd service. Its status is better than nothing.
+        Err(e) if report.daemon.is_some() => {
+            log::warn!("Only the daemon's status is shown: {}", e);
+            return Ok(Exit::Success);
+        }
+        Err(e) => return Err(e),
+    };
+    report.backend = Some(login_task.backend_name());
+
+    match args.subcommand() {
+        Some(("login", args)) => {
+            let (max_attempts, timeout) = login_limits(args);
+            let result = oneshot(&login_task, max_attempts, timeout, report);
+            report.account = Some(login_task.account().to_string());
+            result
+        }
+        Some(("logout", _)) => {
+            login_task.logout()?;
+            log::info!("Logout successfully.");
+            Ok(Exit::Success)
+        }
+        Some(("status", _)) => {
+            report.session = login_task.status()?;
+            report.account = report.session.as_ref().map(|s| s.username.clone());
+            report.captive_portal = discovery.query_api().and_then(Result::ok);
+            Ok(Exit::Success)
+        }
+        Some(("check", _)) => {
+            let (state, portal_url) = login_task.detect_network();
+            report.state = Some(state);
+            report.portal_url = portal_url;
+            Ok(match state {
+                NetState::Online => Exit::Success,
+                NetState::CaptivePortal => Exit::CaptivePortal,
+                NetState::NoNetwork => Exit::NoNetwork,
+            })
+        }
+        _ if args.get_flag("oneshot") => {
+            let (max_attempts, timeout) = login_limits(args);
+            let result = oneshot(&login_task, max_attempts, timeout, report);
+            report.account = Some(login_task.account().to_string());
+            result
+        }
+        _ => daemon(source, login_task),
+    }
+}
+
+/// Send `request` to the running daemon and print its response.
+fn forward(request: Request) -> anyhow::Result<Exit> {
+    let response = control::send(request)
+        .map_err(|e| anyhow::anyhow!("Failed to reach the daemon: {} Is it running?", e))?;
+
+    reply(response)
+}
+
+fn reply(response: Response) -> anyhow::Result<Exit> {
+    if !response.ok {
+        return Err(anyhow::anyhow!(response.message));
+    }
+    println!("{}", response.message);
+
+    Ok(Exit::Success)
+}
+
+/// The result of a command, printed as a JSON object with `--output json`.
+///
+/// Every field is always present, and is `null` if it does not apply to the command. Fields are only ever added, never renamed or removed.
+#[derive(Debug, Serialize)]
+struct Report {
+    command: String,
+    /// `online`, `captive_portal` or `no_network`.
+    state: Option<NetState>,
+    portal_url: Option<String>,
+    backend: Option<&'static str>,
+    /// The account in use: the one that logged in, or the one the portal reports.
+    account: Option<String>,
+    /// What the running daemon is doing, reported by `status`.
+    daemon: Option<TaskStatus>,
+    /// How often the login task of the running daemon has crashed, reported by `status`.
+    health: Option<Health>,
+    /// Login attempts made by this command.
+    attempts: Option<u32>,
+    error: Option<ErrorReport>,
+    session: Option<SessionInfo>,
+    /// What the Captive Portal API says, reported by `status` if the network has one.
+    captive_portal: Option<CaptivePortalState>,
+    exit_code: u8,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorReport {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl Report {
+    fn new(command: &str) -> Self {
+        Report {
+            command: command.to_string(),
+            state: None,
+            portal_url: None,
+            backend: None,
+            account: None,
+            daemon: None,
+            health: None,
+            attempts: None,
+            error: None,
+            session: None,
+            captive_portal: None,
+            exit_code: 0,
+        }
+    }
+
+    fn finish(&mut self, result: &anyhow::Result<Exit>) {
+        let exit = match result {
+            Ok(exit) => *exit,
+            Err(e) => {
+                self.error = Some(ErrorReport {
+                    kind: classify(e),
+                    message: e.to_string(),
+                });
+                Exit::from_error(e)
+            }
+        };
+        self.exit_code = exit as u8;
+    }
+
+    /// Print the result for humans. Logs already tell how `login` and `logout` went.
+    fn print(&self) {
+        match self.command.as_str() {
+            "status" => {
+                if let Some(daemon) = &self.daemon {
+                    let state = daemon.state.map(|state| state.to_string());
+                    let mut line = format!(
+                        "Daemon:      {}, account {}",
+                        state.as_deref().unwrap_or("starting"),
+                        daemon.account
+                    );
+                    if daemon.paused {
+                        line.push_str(", paused");
+                    }
+                    if daemon.locked_out {
+                        line.push_str(", stopped after rejected credentials");
+                    }
+                    println!("{}", line);
+                    if let Some(e) = &daemon.last_error {
+                        println!("Last error:  {}", e);
+                    }
+                }
+                if let Some(health) = self.health.as_ref().filter(|health| health.restarts > 0) {
+                    println!(
+                        "Restarts:    {}, last crash: {}",
+                        health.restarts,
+                        health.last_crash.as_deref().unwrap_or("none")
+                    );
+                }
+                // Without a config only the daemon was asked, not the portal.
+                if self.backend.is_some() {
+                    match &self.session {
+                        Some(session) => println!("{}", session),
+                        None => println!("You are not logged in."),
+                    }
+                }
+                if let Some(state) = &self.captive_portal {
+                    println!("{}", state);
+                }
+            }
+            "check" => {
+                if let Some(state) = self.state {
+                    println!("{}", state);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Run `run` method of the login task until we receive SIGINT or SIGTERM, and answer requests on the control socket meanwhile.
+fn daemon(source: &ConfigSource, login_task: LoginTask) -> anyhow::Result<Exit> {
+    log::info!("Running in daemon mode.");
+
+    if control::send(Request::Status).is_ok() {
+        return Err(anyhow::anyhow!("Another daemon is already running."));
+    }
+
+    // Requests from the control socket, each with a channel for the response.
+    let (tx_control, rx_control) = mpsc::channel();
+    let _server = match ControlServer::start(&control::socket_path(), tx_control) {
+        Ok(server) => Some(server),
+        Err(e) => {
+            log::warn!("Control socket is unavailable: {}", e);
+            None
+        }
+    };
+
+    let mut worker = Worker::spawn(login_task);
+
+    let term = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&term))?;
+    signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&term))?;
+
+    while !term.load(Ordering::Relaxed) && !worker.handle.is_finished() {
+        // Reports from the login task. Nothing here shows them, but they help debugging.
+        for event in worker.events.try_iter() {
+            log::debug!("Event from the login task: {:?}", event);
+        }
+
+        let (request, reply) = match rx_control.recv_timeout(Duration::from_secs(1)) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+
+        let response = match request {
+            Request::Status => Response {
+                status: Some(worker.status.lock().unwrap().clone()),
+                health: Some(worker.health.lock().unwrap().clone()),
+                ..Response::ok("The daemon is running.")
+            },
+            Request::Login => worker.send(AppEvent::ForceLogin, "Login requested."),
+            Request::Logout => {
+                worker.logout(reply);
+                continue;
+            }
+            Request::Pause => worker.send(AppEvent::Pause, "Paused."),
+            Request::Resume => worker.send(AppEvent::Resume, "Resumed."),
+            Request::Reload => {
+                let response;
+                (worker, response) = reload(source, worker)?;
+                response
+            }
+        };
+        let _ = reply.send(response);
+    }
+    worker.stop()?;
+
+    Ok(Exit::Success)
+}
+
+/// Load the config again and replace the login task with a new one. The old task keeps running if the config is broken.
+fn reload(source: &ConfigSource, worker: Worker) -> anyhow::Result<(Worker, Response)> {
+    let login_task = match source.load().and_then(LoginTask::new) {
+        Ok(login_task) => login_task,
+        Err(e) => {
+            let response = Response::error(format!("Failed to reload config: {}", e));
+            return Ok((worker, response));
+        }
+    };
+
+    worker.stop()?;
+    log::info!("Config reloaded.");
+
+    Ok((Worker::spawn(login_task), Response::ok("Config reloaded.")))
+}
+
+/// The login task running in its own thread, restarted by a supervisor when it crashes.
+struct Worker {
+    sender: Sender<AppEvent>,
+    /// What the login task reports.
+    events: Receiver<AppEvent>,
+    status: Arc<Mutex<TaskStatus>>,
+    health: Arc<Mutex<Health>>,
+    handle: JoinHandle<anyhow::Result<()>>,
+}
+
+impl Worker {
+    fn spawn(login_task: LoginTask) -> Self {
+        let (tx_login, rx_login) = mpsc::channel();
+        let (tx_main, rx_main) = mpsc::channel();
+        let supervisor = Supervisor::new("login", login_task);
+        let status = supervisor.task().status_handle();
+        let health = supervisor.health_handle();
+        let handle = thread::spawn(move || supervisor.run(tx_main, rx_login));
+
+        Worker {
+            sender: tx_login,
+            events: rx_main,
+            status,
+            health,
+            handle,
+        }
+    }
+
+    fn send(&self, event: AppEvent, message: &str) -> Response {
+        match self.sender.send(event) {
+            Ok(()) => Response::ok(message),
+            Err(_) => Response::error("The login task has stopped."),
+        }
+    }
+
+    /// Logout through the task and send its result to `reply`. The answer comes from another thread, since logging out with a browser takes a while.
+    fn logout(&self, reply: Sender<Response>) {
+        let (tx, rx) = mpsc::channel();
+        if self.sender.send(AppEvent::Logout(Some(tx))).is_err() {
+            let _ = reply.send(Response::error("The login task has stopped."));
+            return;
+        }
+
+        thread::spawn(move || {
+            let response = match rx.recv() {
+                Ok(Ok(())) => {
+                    Response::ok("Logout successfully, the daemon is paused until resumed.")
+                }
+                Ok(Err(e)) => Response::error(format!("Logout failed: {}", e)),
+                Err(_) => Response::error("The login task stopped before logging out."),
+            };
+            let _ = reply.send(response);
+        });
+    }
+
+    /// Tell the task to quit and wait for it.
+    fn stop(self) -> anyhow::Result<()> {
+        let _ = self.sender.send(AppEvent::Quit);
+        self.handle
+            .join()
+            .map_err(|_| anyhow::anyhow!("The login task supervisor panicked."))?
+    }
+}
+
+/// How long `oneshot` waits for the network to come back before it looks again.
+const NETWORK_WAIT: Duration = Duration::from_secs(5);
+
+/// Run `login` method directly until we are online, or give up after `max_attempts` attempts or `timeout`.
+fn oneshot(
+    login_task: &LoginTask,
+    max_attempts: Option<u32>,
+    timeout: Option<Duration>,
+    report: &mut Report,
+) -> anyhow::Result<Exit> {
+    log::info!("Running in oneshot mode.");
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    let mut attempts = 0;
+    report.attempts = Some(attempts);
+    let (mut state, portal_url) = login_task.detect_network();
+    report.state = Some(state);
+    report.portal_url = portal_url;
+    if state == NetState::Online {
+        log::info!("You are already online.");
+        return Ok(Exit::AlreadyOnline);
+    }
+
+    let mut backoff = login_task.backoff();
+    if let Some(max_attempts) = max_attempts {
+        backoff = backoff.with_max_attempts(max_attempts);
+    }
+
+    let mut last_error = None;
+    let mut exhausted = false;
+    loop {
+        // Detecting the network or the last check may have used up the time. An attempt already running is not interrupted.
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break;
+        }
+
+        // Login only when the portal intercepts our traffic, otherwise wait for the network.
+        let attempted = state == NetState::CaptivePortal;
+        if attempted {
+            attempts += 1;
+            report.attempts = Some(attempts);
+            if let Err(e) = login_task.login() {
+                // Give up on errors that retrying does not fix, e.g. a wrong password.
+                if !classify(&e).is_retryable() {
+                    return Err(e);
+                }
+                log::error!("{}", e);
+                last_error = Some(e);
+            }
+        } else {
+            log::info!("Network is down, wait for it to come back.");
+        }
+
+        // Wait a second for network to be ready.
+        thread::sleep(Duration::from_secs(1));
+        state = login_task.check_network();
+        report.state = Some(state);
+        if state == NetState::Online {
+            log::info!("You are online.");
+            return Ok(Exit::Success);
+        }
+
+        // Hang up for next login attempt to avoid being banned. Waiting for the network does not use up an attempt.
+        let delay = if attempted {
+            match backoff.next_delay() {
+                Some(delay) => delay,
+                None => {
+                    exhausted = true;
+                    break;
+                }
+            }
+        } else {
+            NETWORK_WAIT
+        };
+        // Give up if there is no time left for the next attempt.
+        if deadline.is_some_and(|deadline| Instant::now() + delay >= deadline) {
+            break;
+        }
+        thread::sleep(delay);
+    }
+
+    // Tell a missing portal apart from a portal that does not let us in.
+    if state == NetState::NoNetwork {
+        return Err(LoginError::new(
+            ErrorKind::PortalUnreachable,
+            "Portal not found, please check your network connection.",
+        )
+        .into());
+    }
+    let (kind, mut message) = if exhausted {
+        (
+            ErrorKind::AttemptsExhausted,
+            format!("Still offline after all {} attempts.", attempts),
+        )
+    } else {
+        (
+            ErrorKind::TimedOut,
+            format!(
+                "Still offline when the time ran out, after {} attempts.",
+                attempts
+            ),
+        )
+    };
+    if let Some(e) = last_error {
+        message = format!("{} Last error: {}", message, e);
+    }
+    Err(LoginError::new(kind, message).into())
+}
+
+fn config_show(source: &ConfigSource, redacted: bool) -> anyhow::Result<Exit> {
+    let mut config = source.load()?;
+    if redacted {
+        for account in std::iter::once(&mut config.account).chain(&mut config.accounts) {
+            if !account.password.is_empty() {
+                account.password = Secret::new(REDACTED);
+            }
+        }
+    }
+    print!("{}", serde_yaml::to_string(&config)?);
+
+    Ok(Exit::Success)
+}
+
+fn config_validate(source: &ConfigSource) -> anyhow::Result<Exit> {
+    let config = source.load()?;
+    create_backend(&config)?;
+    println!("Config file {} is valid.", source.path.display());
+
+    Ok(Exit::Success)
+}
+
+fn config_init(config_file: &Path, force: bool) -> anyhow::Result<Exit> {
+    if config_file.exists() && !force {
+        return Err(anyhow::anyhow!(
+            "Config file {} already exists. Use --force to overwrite it.",
+            config_file.display()
+        ));
+    }
+
+    if let Some(parent) = config_file.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // The template asks for a password, so only the owner may read it.
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(config_file)?;
+    // `mode` only applies to new files, an overwritten one keeps its permissions otherwise.
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    std::io::Write::write_all(&mut file, CONFIG_TEMPLATE.as_bytes())?;
+    println!(
+        "Config file {} is created. Please fill in your username and password.",
+        config_file.display()
+    );
+
+    Ok(Exit::Success)
+}
+
+/// Print everything that matters for logging in, and go on even if some of it fails.
+fn diagnose(source: &ConfigSource) -> anyhow::Result<Exit> {
+    println!("Version: {}", env!("CARGO_PKG_VERSION"));
+    println!(
+        "Browser backend: {}",
+        if cfg!(feature = "browser") {
+            "built in"
+        } else {
+            "not built in"
+        }
+    );
+    #[cfg(feature = "browser")]
+    match xdwlan_login::backends::BrowserBackend::executable() {
+        Some(path) => println!("Browser: {}", path.display()),
+        None => println!("Browser: not found"),
+    }
+
+    let search_paths: Vec<_> = search_paths()
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    println!("Config search paths: {}", search_paths.join(", "));
+    println!("Config file: {}", source.path.display());
+    let config = match source.load() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Config: {}", e);
+            return Ok(Exit::Failure);
+        }
+    };
+    println!("Config: ok");
+
+    diagnose_network(&config);
+
+    let backend = match create_backend(&config) {
+        Ok(backend) => backend,
+        Err(e) => {
+            println!("Backend: {}", e);
+            return Ok(Exit::Failure);
+        }
+    };
+    println!("Backend: {}", backend.name());
+
+    let state = xdwlan_login::probe::classify(&config.connectivity, backend.as_ref());
+    println!("Network: {}", state);
+
+    match backend.query_session() {
+        Ok(Some(session)) => println!("Session:\n{}", session),
+        Ok(None) => println!("Session: not logged in"),
+        Err(e) => println!("Session: {}", e),
+    }
+
+    Ok(Exit::Success)
+}
+
+fn diagnose_network(config: &Config) {
+    let connectivity = &config.connectivity;
+    for probe in &connectivity.probes {
+        let timeout = Duration::from_secs(probe.timeout.unwrap_or(connectivity.timeout));
+        let start = Instant::now();
+        let result = probe.probe.check(timeout);
+        let elapsed = start.elapsed().as_millis();
+        match result {
+            Ok(()) => println!("Probe {:?}: ok ({} ms)", probe.probe, elapsed),
+            Err(e) => println!("Probe {:?}: {} ({} ms)", probe.probe, e, elapsed),
+        }
+    }
+
+    let discovery = PortalDiscovery::new(config.captive_portal_api.clone(), &config.portal);
+    match discovery.query_api() {
+        Some(Ok(state)) => println!("Captive Portal API: {:?}", state),
+        Some(Err(e)) => println!("Captive Portal API: {}", e),
+        None => println!("Captive Portal API: not advertised"),
+    }
+    match discovery.scrape_login_url() {
+        Ok(Some(url)) => println!("Login page: {}", url),
+        Ok(None) => println!("Login page: not intercepted"),
+        Err(e) => println!("Login page: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_is_valid() {
+        command().debug_assert();
+    }
+
+    #[test]
+    fn oneshot_takes_login_limits() {
+        let args = command()
+            .try_get_matches_from([
+                "xdwlan-login",
+                "--oneshot",
+                "--max-attempts",
+                "3",
+                "--timeout",
+                "60",
+            ])
+            .unwrap();
+        assert_eq!(
+            login_limits(&args),
+            (Some(3), Some(Duration::from_secs(60)))
+        );
+
+        let args = command()
+            .try_get_matches_from(["xdwlan-login", "login", "--max-attempts", "2"])
+            .unwrap();
+        let (_, args) = args.subcommand().unwrap();
+        assert_eq!(login_limits(args), (Some(2), None));
+
+        // The limits mean nothing to the daemon.
+        assert!(command()
+            .try_get_matches_from(["xdwlan-login", "--max-attempts", "3"])
+            .is_err());
+    }
+}
@@ -1,39 +1,646 @@
 use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize)]
+use crate::secret::{PasswordSource, Secret};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
+    /// The account to login with. It may be left out if `accounts` are given, or if it is given by `Overrides`.
+    #[serde(flatten)]
+    pub account: Account,
+
+    /// More accounts, tried in order when the one in use hits its device limit or runs out of balance.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub accounts: Vec<Account>,
+
+    /// The operator package to login with.
+    #[serde(default)]
+    pub isp: Isp,
+
+    /// How to log in: `browser` drives a Chromium-based browser, `http` talks to the portal API directly.
+    #[serde(default)]
+    pub backend: BackendKind,
+
+    /// Base URL of the Srun portal. The `http` backend logs in through it, and both backends query the session and logout through it.
+    #[serde(default = "default_portal")]
+    pub portal: String,
+
+    /// The portal's access controller id. It is discovered from the portal if not set.
+    #[serde(default)]
+    pub ac_id: Option<String>,
+
+    /// URI of the Captive Portal API (RFC 8908). On Linux, it is discovered from DHCP if not set.
+    #[serde(default)]
+    pub captive_portal_api: Option<String>,
+
+    /// How to tell whether we are online.
+    #[serde(default)]
+    pub connectivity: ConnectivityConfig,
+
+    /// Seconds between two connectivity checks when we are online.
+    #[serde(default = "default_check_interval")]
+    pub check_interval: u64,
+
+    /// How to wait between login attempts.
+    #[serde(default)]
+    pub retry: RetryPolicy,
+}
+
+impl Config {
+    /// All accounts in the order they are tried: the top-level one if given, then `accounts`.
+    pub fn accounts(&self) -> anyhow::Result<Vec<Account>> {
+        let mut accounts = Vec::new();
+        if !self.account.is_empty() {
+            accounts.push(self.account.clone());
+        }
+        accounts.extend(self.accounts.iter().cloned());
+
+        if accounts.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No username given. Please set it in the config file or XDWLAN_USERNAME."
+            ));
+        }
+        for account in &accounts {
+            if account.username.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "An account has a password but no username."
+                ));
+            }
+            account
+                .password_source()
+                .map_err(|e| anyhow::anyhow!("Account {}: {}", account.username, e))?;
+        }
+
+        Ok(accounts)
+    }
+
+    /// Reject settings that parse but can not work.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        // Zero would check the network in a busy loop.
+        if self.check_interval == 0 {
+            return Err(anyhow::anyhow!("check_interval must be at least 1 second."));
+        }
+        self.connectivity.validate()?;
+        self.retry.validate()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Account {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub username: String,
-    pub password: String,
+
+    /// Only one of `password`, `password_file`, `password_command` and `password_keyring` may be set.
+    #[serde(default, skip_serializing_if = "Secret::is_empty")]
+    pub password: Secret,
+
+    /// Read the password from the first line of this file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<PathBuf>,
+
+    /// Read the password from the first line printed by this shell command, e.g. `pass show xidian`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_command: Option<String>,
+
+    /// Look up the password in the Secret Service (GNOME Keyring, KWallet) with `secret-tool`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_keyring: Option<KeyringConfig>,
+}
+
+impl Account {
+    fn is_empty(&self) -> bool {
+        self.username.is_empty()
+            && self.password.is_empty()
+            && self.password_file.is_none()
+            && self.password_command.is_none()
+            && self.password_keyring.is_none()
+    }
+
+    /// Where to read the password from.
+    pub fn password_source(&self) -> anyhow::Result<PasswordSource> {
+        let mut sources = Vec::new();
+        if !self.password.is_empty() {
+            sources.push(PasswordSource::Plain(self.password.clone()));
+        }
+        if let Some(path) = &self.password_file {
+            sources.push(PasswordSource::File(path.clone()));
+        }
+        if let Some(command) = &self.password_command {
+            sources.push(PasswordSource::Command(command.clone()));
+        }
+        if let Some(keyring) = &self.password_keyring {
+            sources.push(PasswordSource::Keyring {
+                service: keyring.service.clone(),
+                account: keyring
+                    .account
+                    .clone()
+                    .unwrap_or_else(|| self.username.clone()),
+            });
+        }
+
+        match sources.len() {
+            0 => Err(anyhow::anyhow!(
+                "No password given. Please set one of password, password_file, password_command and password_keyring in the config file, or XDWLAN_PASSWORD."
+            )),
+            1 => Ok(sources.remove(0)),
+            _ => Err(anyhow::anyhow!(
+                "Only one of password, password_file, password_command and password_keyring may be set."
+            )),
+        }
+    }
+}
+
+/// The Secret Service item holding the password, with attributes `service` and `username`. This is how `secret-tool store` and Python's `keyring` save it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyringConfig {
+    #[serde(default = "default_keyring_service")]
+    pub service: String,
+
+    /// Defaults to the username.
+    #[serde(default)]
+    pub account: Option<String>,
+}
+
+fn default_keyring_service() -> String {
+    "xdwlan-login".to_string()
+}
+
+/// Besides the campus network, the portal sells operator packages. It tells them apart by a domain suffix on the username.
+///
+/// Set `isp` in the config to login with an operator package instead of the campus network. Usernames in the config stay without the suffix, the backends add it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Isp {
+    #[default]
+    Campus,
+    /// China Telecom.
+    #[serde(alias = "dx")]
+    Telecom,
+    /// China Unicom.
+    #[serde(alias = "lt")]
+    Unicom,
+    /// China Mobile.
+    #[serde(alias = "yd")]
+    Mobile,
+}
+
+impl Isp {
+    /// The domain suffix appended to the username, e.g. `@dx`.
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Isp::Campus => "",
+            Isp::Telecom => "@dx",
+            Isp::Unicom => "@lt",
+            Isp::Mobile => "@yd",
+        }
+    }
+}
+
+/// The default backend is `browser` unless the program is built without the `browser` feature.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[cfg_attr(feature = "browser", default)]
+    Browser,
+    #[cfg_attr(not(feature = "browser"), default)]
+    Http,
+}
+
+fn default_portal() -> String {
+    "https://w.xidian.edu.cn".to_string()
+}
+
+fn default_check_interval() -> u64 {
+    60
+}
+
+/// The delay before the n-th retry is `initial_delay * multiplier^(n-1)`, capped at `max_delay`, and then randomized by `jitter` so that machines do not retry in lockstep.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Seconds to wait before the first retry.
+    pub initial_delay: f64,
+    pub multiplier: f64,
+    /// Upper bound of the delay, in seconds.
+    pub max_delay: f64,
+    /// The delay is randomized within `±jitter` of itself, e.g. `0.2` for ±20%.
+    pub jitter: f64,
+    /// Give up after this many attempts. Retry forever if not set.
+    pub max_attempts: Option<u32>,
+}
+
+/// The longest delay between two attempts, in seconds.
+pub const MAX_RETRY_DELAY: f64 = 86400.0;
+
+impl RetryPolicy {
+    /// Delays must be numbers of seconds up to a day, the multiplier must not shrink the delay, and the jitter is a fraction.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (name, delay) in [
+            ("initial_delay", self.initial_delay),
+            ("max_delay", self.max_delay),
+        ] {
+            if !(0.0..=MAX_RETRY_DELAY).contains(&delay) {
+                return Err(anyhow::anyhow!(
+                    "retry.{} must be between 0 and {} seconds, got {}.",
+                    name,
+                    MAX_RETRY_DELAY,
+                    delay
+                ));
+            }
+        }
+        if !(self.multiplier.is_finite() && self.multiplier >= 1.0) {
+            return Err(anyhow::anyhow!(
+                "retry.multiplier must be at least 1, got {}.",
+                self.multiplier
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err(anyhow::anyhow!(
+                "retry.jitter must be between 0 and 1, got {}.",
+                self.jitter
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial_delay: 5.0,
+            multiplier: 2.0,
+            max_delay: 300.0,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+/// The probes to run for checking connectivity, and how many of them must succeed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectivityConfig {
+    #[serde(default = "default_probes")]
+    pub probes: Vec<ProbeConfig>,
+
+    #[serde(default)]
+    pub policy: ProbePolicy,
+
+    /// Default timeout of a probe, in seconds.
+    #[serde(default = "default_probe_timeout")]
+    pub timeout: u64,
+}
+
+impl ConnectivityConfig {
+    /// Without probes we could never tell that we are online, and a quorum larger than the probes can never be reached.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.probes.is_empty() {
+            return Err(anyhow::anyhow!(
+                "connectivity.probes is empty. Please give at least one probe, or remove it to use the defaults."
+            ));
+        }
+        if let ProbePolicy::Quorum(n) = self.policy {
+            if n > self.probes.len() {
+                return Err(anyhow::anyhow!(
+                    "connectivity.policy requires {} probes to succeed, but there are only {}.",
+                    n,
+                    self.probes.len()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for ConnectivityConfig {
+    fn default() -> Self {
+        ConnectivityConfig {
+            probes: default_probes(),
+            policy: ProbePolicy::default(),
+            timeout: default_probe_timeout(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProbeConfig {
+    #[serde(flatten)]
+    pub probe: Probe,
+
+    /// Overrides the default timeout, in seconds.
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Probe {
+    /// The url responds `204 No Content`.
+    Http204 { url: String },
+    /// The url responds `200 OK` and the body contains the given text.
+    HttpBody { url: String, contains: String },
+    /// A TCP connection to `host:port` can be established.
+    Tcp { address: String },
+    /// The host name can be resolved. Note that the portal usually still answers DNS queries.
+    Dns { host: String },
+}
+
+/// `any` (the default), `all`, or the number of probes that must succeed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "ProbePolicyRepr", into = "ProbePolicyRepr")]
+pub enum ProbePolicy {
+    #[default]
+    Any,
+    All,
+    Quorum(usize),
 }
 
-pub fn load_config() -> anyhow::Result<Config> {
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum ProbePolicyRepr {
+    Quorum(usize),
+    Name(String),
+}
+
+impl TryFrom<ProbePolicyRepr> for ProbePolicy {
+    type Error = String;
+
+    fn try_from(repr: ProbePolicyRepr) -> Result<Self, Self::Error> {
+        match repr {
+            ProbePolicyRepr::Quorum(0) => Err("quorum must be at least 1".to_string()),
+            ProbePolicyRepr::Quorum(n) => Ok(ProbePolicy::Quorum(n)),
+            ProbePolicyRepr::Name(name) => match name.as_str() {
+                "any" => Ok(ProbePolicy::Any),
+                "all" => Ok(ProbePolicy::All),
+                _ => Err(format!(
+                    "unknown policy `{}`, expected `any`, `all` or a number",
+                    name
+                )),
+            },
+        }
+    }
+}
+
+impl From<ProbePolicy> for ProbePolicyRepr {
+    fn from(policy: ProbePolicy) -> Self {
+        match policy {
+            ProbePolicy::Any => ProbePolicyRepr::Name("any".to_string()),
+            ProbePolicy::All => ProbePolicyRepr::Name("all".to_string()),
+            ProbePolicy::Quorum(n) => ProbePolicyRepr::Quorum(n),
+        }
+    }
+}
+
+fn default_probes() -> Vec<ProbeConfig> {
+    [
+        "http://wifi.vivo.com.cn/generate_204",
+        "http://connect.rom.miui.com/generate_204",
+    ]
+    .into_iter()
+    .map(|url| ProbeConfig {
+        probe: Probe::Http204 {
+            url: url.to_string(),
+        },
+        timeout: None,
+    })
+    .collect()
+}
+
+fn default_probe_timeout() -> u64 {
+    5
+}
+
+/// A starting point for a new config file.
+pub const CONFIG_TEMPLATE: &str = r#"# Your student id and password.
+username: <username>
+password: <password>
+
+# How to log in: `browser` drives a Chromium-based browser, `http` talks to the portal API directly.
+# backend: browser
+
+# Login with an operator package: `campus` (default), `telecom` (@dx), `unicom` (@lt) or `mobile` (@yd).
+# isp: campus
+
+# Seconds between two connectivity checks when you are online.
+# check_interval: 60
+"#;
+
+/// The environment variable that points to the config file, for when `--config` can not be passed.
+pub const CONFIG_ENV: &str = "XDWLAN_LOGIN_CONFIG";
+
+/// Where to look for the config file if none is given explicitly, in order.
+pub fn search_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+
     // On windows, the config file is expected to be in the same folder as the executable.
     #[cfg(target_os = "windows")]
-    let config_file_path = format!("{}/config.yaml", crate::utils::get_program_folder());
+    paths.push(PathBuf::from(crate::utils::get_program_folder()).join("config.yaml"));
 
-    // On linux, the config file is expected to be in the $XDG_CONFIG_HOME/xdwlan-login folder.
+    // On linux, the config file is expected to be in the $XDG_CONFIG_HOME/xdwlan-login folder, or in /etc/xdwlan-login for system-wide services whose user may have no home.
     #[cfg(target_os = "linux")]
-    let config_file_path = format!(
-        "{}/xdwlan-login/config.yaml",
-        dirs::config_dir().unwrap().to_str().unwrap()
-    );
+    {
+        if let Some(config_dir) = dirs::config_dir() {
+            paths.push(config_dir.join("xdwlan-login/config.yaml"));
+        }
+        paths.push(PathBuf::from("/etc/xdwlan-login/config.yaml"));
+    }
+
+    paths
+}
+
+/// The config file to use: `explicit` (e.g. the `--config` flag), then `$XDWLAN_LOGIN_CONFIG`, then the first existing file of `search_paths`.
+///
+/// If no config file exists, this is where a new one should be created.
+pub fn config_path(explicit: Option<&Path>) -> PathBuf {
+    if let Some(path) = explicit {
+        return path.to_path_buf();
+    }
+    if let Some(path) = std::env::var_os(CONFIG_ENV).filter(|path| !path.is_empty()) {
+        return PathBuf::from(path);
+    }
+
+    let paths = search_paths();
+    paths
+        .iter()
+        .find(|path| path.is_file())
+        .or(paths.first())
+        .cloned()
+        .unwrap_or_else(|| PathBuf::from("config.yaml"))
+}
+
+/// Credentials that take precedence over the config file.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub username: Option<String>,
+    pub password: Option<Secret>,
+}
+
+impl Overrides {
+    /// Read `XDWLAN_USERNAME` and `XDWLAN_PASSWORD`. Empty variables are ignored.
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok().filter(|value| !value.is_empty());
+
+        Overrides {
+            username: var("XDWLAN_USERNAME"),
+            password: var("XDWLAN_PASSWORD").map(Secret::new),
+        }
+    }
+
+    /// Keep the values of `self`, and take the missing ones from `fallback`.
+    pub fn or(self, fallback: Overrides) -> Self {
+        Overrides {
+            username: self.username.or(fallback.username),
+            password: self.password.or(fallback.password),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.username.is_some() && self.password.is_some()
+    }
+}
 
-    let config = match std::fs::read_to_string(&config_file_path) {
+/// Load the config file and apply `overrides` on top of it.
+///
+/// The config file may be missing if `overrides` provide both username and password, e.g. in a container. Any override disables `accounts`, so the credentials given on the command line or in the environment never fail over to an account from the file.
+pub fn load_config(config_file_path: &Path, overrides: &Overrides) -> anyhow::Result<Config> {
+    let config = match std::fs::read_to_string(config_file_path) {
         Ok(config) => config,
+        Err(e) if e.kind() == ErrorKind::NotFound && overrides.is_complete() => {
+            log::debug!(
+                "Config file {} not found, use defaults.",
+                config_file_path.display()
+            );
+            "{}".to_string()
+        }
         Err(e) if e.kind() == ErrorKind::NotFound => {
             return Err(anyhow::anyhow!(
                 "Config file {} not found. Please create one.",
-                config_file_path
-            )
-            .into());
+                config_file_path.display()
+            ));
         }
         Err(e) => {
             return Err(e.into());
         }
     };
-    let config: Config = serde_yaml::from_str(&config)?;
+    let mut config: Config = serde_yaml::from_str(&config)?;
+    config.validate()?;
+
+    if overrides.username.is_some() || overrides.password.is_some() {
+        if !config.accounts.is_empty() {
+            log::info!("Credentials are overridden, `accounts` in the config file are ignored.");
+        }
+        config.accounts.clear();
+    }
+    if let Some(username) = &overrides.username {
+        config.account.username = username.clone();
+    }
+    if let Some(password) = &overrides.password {
+        config.account.password = password.clone();
+        config.account.password_file = None;
+        config.account.password_command = None;
+        config.account.password_keyring = None;
+    }
+    for account in config.accounts()? {
+        if config.isp != Isp::Campus && account.username.contains('@') {
+            return Err(anyhow::anyhow!(
+                "Username {} already has a domain suffix. Please remove it, `isp` appends the right one.",
+                account.username
+            ));
+        }
+        crate::logger::redact(account.password.expose());
+    }
 
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(yaml: &str) -> anyhow::Result<Config> {
+        let config: Config = serde_yaml::from_str(&format!("username: a\npassword: b\n{}", yaml))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn default_probes_are_valid() {
+        assert!(parse("").is_ok());
+    }
+
+    #[test]
+    fn empty_probes_are_rejected() {
+        let e = parse("connectivity:\n  probes: []").unwrap_err();
+        assert!(e.to_string().contains("probes is empty"), "{}", e);
+    }
+
+    #[test]
+    fn quorum_larger_than_probes_is_rejected() {
+        let yaml = "connectivity:
+  policy: 3
+  probes:
+    - type: dns
+      host: example.com
+    - type: dns
+      host: example.org
+";
+        let e = parse(yaml).unwrap_err();
+        assert!(e.to_string().contains("only 2"), "{}", e);
+        assert!(parse(&yaml.replace("policy: 3", "policy: 2")).is_ok());
+    }
+
+    #[test]
+    fn broken_retry_policies_are_rejected() {
+        for retry in [
+            "max_delay: 1e300",
+            "max_delay: .inf",
+            "initial_delay: .nan",
+            "initial_delay: -1",
+            "multiplier: 0.5",
+            "multiplier: .inf",
+            "jitter: 1.5",
+            "jitter: -0.1",
+        ] {
+            let yaml = format!("retry:\n  {}", retry);
+            assert!(parse(&yaml).is_err(), "{}", retry);
+        }
+        assert!(parse("retry:\n  max_delay: 86400\n  jitter: 0").is_ok());
+    }
+
+    #[test]
+    fn zero_check_interval_is_rejected() {
+        let e = parse("check_interval: 0").unwrap_err();
+        assert!(e.to_string().contains("check_interval"));
+        assert!(parse("check_interval: 1").is_ok());
+    }
+
+    #[test]
+    fn overrides_disable_accounts() {
+        let path = std::env::temp_dir().join(format!(
+            "xdwlan-login-overrides-{}.yaml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            "username: a\npassword: b\naccounts:\n  - username: c\n    password: d\n",
+        )
+        .unwrap();
+
+        let config = load_config(&path, &Overrides::default()).unwrap();
+        assert_eq!(config.accounts().unwrap().len(), 2);
+
+        let overrides = Overrides {
+            username: Some("e".to_string()),
+            password: None,
+        };
+        let config = load_config(&path, &overrides).unwrap();
+        let accounts = config.accounts().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].username, "e");
+        assert_eq!(accounts[0].password.expose(), "b");
+    }
+}
@@ -0,0 +1,256 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::tasks::{Health, TaskStatus};
+
+const SOCKET_NAME: &str = "xdwlan-login.sock";
+
+/// How long the daemon may take to answer, e.g. a logout waits for a login attempt already running.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A request to the running daemon. On the wire, it is a JSON object on a single line, e.g. `{"command":"pause"}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    /// Login right away.
+    Login,
+    /// Logout and pause.
+    Logout,
+    Pause,
+    Resume,
+    /// Load the config file again.
+    Reload,
+}
+
+/// The daemon answers every request with a JSON object on a single line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    pub message: String,
+    /// Only set for `Status`.
+    #[serde(default)]
+    pub status: Option<TaskStatus>,
+    /// Only set for `Status`.
+    #[serde(default)]
+    pub health: Option<Health>,
+}
+
+impl Response {
+    pub fn ok(message: impl Into<String>) -> Self {
+        Response {
+            ok: true,
+            message: message.into(),
+            status: None,
+            health: None,
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Response {
+            ok: false,
+            message: message.into(),
+            status: None,
+            health: None,
+        }
+    }
+}
+
+/// Where the daemon listens: `/run/user/<uid>/xdwlan-login.sock` if the user has a runtime folder, otherwise `/tmp/xdwlan-login-<uid>/xdwlan-login.sock`. Only the uid counts, not environment variables like `XDG_RUNTIME_DIR`, so the systemd service and a shell agree.
+pub fn socket_path() -> PathBuf {
+    let [runtime, fallback] = socket_paths();
+    if runtime.parent().is_some_and(Path::is_dir) {
+        runtime
+    } else {
+        fallback
+    }
+}
+
+/// The runtime folder may appear after the daemon started, e.g. when the user logs in after the systemd service started at boot, so clients look in both places.
+fn socket_paths() -> [PathBuf; 2] {
+    let uid = uid();
+    [
+        PathBuf::from(format!("/run/user/{}", uid)).join(SOCKET_NAME),
+        PathBuf::from(format!("/tmp/xdwlan-login-{}", uid)).join(SOCKET_NAME),
+    ]
+}
+
+fn uid() -> u32 {
+    // SAFETY: `getuid` has no preconditions and always succeeds.
+    unsafe { libc::getuid() }
+}
+
+/// Create `dir` if missing, and make sure that nobody else may enter it. Then nobody else can reach a socket inside, whatever its own permissions are.
+fn ensure_private_dir(dir: &Path) -> anyhow::Result<()> {
+    match std::fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    // A folder in the shared temporary folder may have been created by someone else beforehand.
+    let metadata = std::fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != uid() || metadata.mode() & 0o077 != 0 {
+        return Err(anyhow::anyhow!(
+            "{} must be a folder owned by the current user, with no permissions for others.",
+            dir.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Listens on the control socket, and passes every request along with a channel for the response to `handler`. The socket is removed when the server is dropped.
+pub struct ControlServer {
+    path: PathBuf,
+}
+
+impl ControlServer {
+    pub fn start(
+        path: &Path,
+        handler: Sender<(Request, Sender<Response>)>,
+    ) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent() {
+            ensure_private_dir(dir)?;
+        }
+
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(anyhow::anyhow!(
+                    "Another daemon is already listening on {}.",
+                    path.display()
+                ));
+            }
+            // Left behind by a daemon that did not quit cleanly.
+            std::fs::remove_file(path)?;
+        }
+
+        // Only the user running the daemon may control it. The private folder already guarantees that, the permissions of the socket are a second line of defence.
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+
+        // Every connection is served in its own thread, so a slow request or a client that never sends one does not block the others.
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::debug!("Control connection failed: {}", e);
+                        continue;
+                    }
+                };
+                let handler = handler.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream, &handler) {
+                        log::debug!("Control connection failed: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(ControlServer {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn serve(stream: UnixStream, handler: &Sender<(Request, Sender<Response>)>) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let response = match serde_json::from_str::<Request>(&line) {
+        Ok(request) => {
+            log::debug!("Control request: {:?}", request);
+            let (tx, rx) = mpsc::channel();
+            handler.send((request, tx))?;
+            rx.recv_timeout(RESPONSE_TIMEOUT)
+                .unwrap_or_else(|_| Response::error("The daemon did not answer in time."))
+        }
+        Err(e) => Response::error(format!("Invalid request: {}", e)),
+    };
+
+    writeln!(&stream, "{}", serde_json::to_string(&response)?)?;
+    Ok(())
+}
+
+/// Send `request` to the running daemon. Fails if no daemon is running.
+pub fn send(request: Request) -> anyhow::Result<Response> {
+    let paths = socket_paths();
+    let stream = paths
+        .iter()
+        .find_map(|path| UnixStream::connect(path).ok())
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "No daemon is listening on {} or {}.",
+                paths[0].display(),
+                paths[1].display()
+            )
+        })?;
+
+    exchange(stream, request)
+}
+
+fn exchange(stream: UnixStream, request: Request) -> anyhow::Result<Response> {
+    // A little longer than the daemon waits itself, so that its own error comes first.
+    stream.set_read_timeout(Some(RESPONSE_TIMEOUT + Duration::from_secs(5)))?;
+    writeln!(&stream, "{}", serde_json::to_string(&request)?)?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn slow_requests_do_not_block_others() {
+        let dir = std::env::temp_dir().join(format!("xdwlan-login-control-{}", std::process::id()));
+        let path = dir.join(SOCKET_NAME);
+        let (tx, rx) = mpsc::channel();
+        let _server = ControlServer::start(&path, tx).unwrap();
+
+        // `Logout` is never answered, like a logout waiting for a login attempt.
+        thread::spawn(move || {
+            let mut pending = Vec::new();
+            for (request, reply) in rx {
+                match request {
+                    Request::Logout => pending.push(reply),
+                    _ => {
+                        let _ = reply.send(Response::ok("The daemon is running."));
+                    }
+                }
+            }
+        });
+
+        // A client that never sends its request.
+        let _idle = UnixStream::connect(&path).unwrap();
+        let logout = UnixStream::connect(&path).unwrap();
+        thread::spawn(move || exchange(logout, Request::Logout));
+
+        let start = Instant::now();
+        let response = exchange(UnixStream::connect(&path).unwrap(), Request::Status).unwrap();
+        assert!(response.ok);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,191 @@
+use std::fmt;
+
+use serde::Serialize;
+
+/// Why a login attempt failed. It decides whether retrying makes sense.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The username or password is wrong.
+    WrongCredentials,
+    /// The account is in arrears or disabled.
+    AccountUnavailable,
+    /// Too many devices are online with this account.
+    DeviceLimit,
+    /// The portal can not be reached, or it does not intercept our traffic.
+    PortalUnreachable,
+    /// The browser can not be launched or driven.
+    BrowserFailure,
+    /// The time limit passed before we got online.
+    TimedOut,
+    /// Every allowed login attempt failed.
+    AttemptsExhausted,
+    Unknown,
+}
+
+impl ErrorKind {
+    /// Retrying with wrong credentials only hammers the portal and risks getting the account locked, and an unavailable account does not recover by itself.
+    pub fn is_retryable(self) -> bool {
+        !matches!(
+            self,
+            ErrorKind::WrongCredentials | ErrorKind::AccountUnavailable
+        )
+    }
+
+    /// Classify a message from the portal, e.g. `E2553: Password is error.` or `密码错误`.
+    ///
+    /// Only specific codes and phrases are matched. Vague words like "exceeded" also appear in the lockout after too many wrong passwords, which must not be retried.
+    pub fn from_message(message: &str) -> Self {
+        const PATTERNS: [(ErrorKind, &[&str]); 3] = [
+            (
+                ErrorKind::WrongCredentials,
+                &[
+                    "e2531",
+                    "e2533",
+                    "e2553",
+                    "e6506",
+                    "password is error",
+                    "user not found",
+                    "ldap_bind",
+                    "密码错误",
+                    "用户不存在",
+                    "账号或密码",
+                    "帐号或密码",
+                    "用户名或密码",
+                ],
+            ),
+            (
+                ErrorKind::AccountUnavailable,
+                &[
+                    "e2606",
+                    "e2616",
+                    "arrearage",
+                    "user is disabled",
+                    "欠费",
+                    "用户被禁用",
+                    "账号被禁用",
+                    "停机",
+                ],
+            ),
+            (
+                ErrorKind::DeviceLimit,
+                &[
+                    // This IP being online already is `ip_already_online_error`, which counts as success. E2620 means the account is online on another device.
+                    "e2620",
+                    "online devices",
+                    "too many devices",
+                    "在线设备数",
+                    "在线设备已达",
+                    "设备数已达上限",
+                    "最大连接数",
+                ],
+            ),
+        ];
+
+        let message = message.to_lowercase();
+        PATTERNS
+            .iter()
+            .find(|(_, keywords)| keywords.iter().any(|keyword| message.contains(keyword)))
+            .map(|(kind, _)| *kind)
+            .unwrap_or(ErrorKind::Unknown)
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            ErrorKind::WrongCredentials => "wrong username or password",
+            ErrorKind::AccountUnavailable => "account is in arrears or disabled",
+            ErrorKind::DeviceLimit => "too many devices online",
+            ErrorKind::PortalUnreachable => "portal unreachable",
+            ErrorKind::BrowserFailure => "browser failure",
+            ErrorKind::TimedOut => "timed out",
+            ErrorKind::AttemptsExhausted => "out of login attempts",
+            ErrorKind::Unknown => "unknown error",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// A login failure with its kind. Backends return it wrapped in `anyhow::Error`.
+#[derive(Debug)]
+pub struct LoginError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl LoginError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        LoginError {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    /// Classify a message from the portal.
+    pub fn from_message(message: impl Into<String>) -> Self {
+        let message = message.into();
+        LoginError::new(ErrorKind::from_message(&message), message)
+    }
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.kind)
+    }
+}
+
+impl std::error::Error for LoginError {}
+
+/// Find out the kind of an error returned by a backend.
+pub fn classify(error: &anyhow::Error) -> ErrorKind {
+    if let Some(e) = error.downcast_ref::<LoginError>() {
+        return e.kind;
+    }
+
+    // Any network error means we can not talk to the portal.
+    if error.downcast_ref::<reqwest::Error>().is_some() {
+        return ErrorKind::PortalUnreachable;
+    }
+
+    ErrorKind::Unknown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_message() {
+        let cases = [
+            ("E2553: Password is error.", ErrorKind::WrongCredentials),
+            ("E2531: User not found.", ErrorKind::WrongCredentials),
+            ("密码错误", ErrorKind::WrongCredentials),
+            ("帐号或密码错误", ErrorKind::WrongCredentials),
+            // The lockout after too many wrong passwords.
+            (
+                "E2533: 密码错误次数超过限制，请5分钟后再重试",
+                ErrorKind::WrongCredentials,
+            ),
+            (
+                "E2533: The number of password errors exceeded the limit.",
+                ErrorKind::WrongCredentials,
+            ),
+            ("E2616: Arrearage users.", ErrorKind::AccountUnavailable),
+            ("E2606: 用户被禁用", ErrorKind::AccountUnavailable),
+            ("您的账户已欠费", ErrorKind::AccountUnavailable),
+            ("在线设备数已达上限", ErrorKind::DeviceLimit),
+            ("Too many online devices.", ErrorKind::DeviceLimit),
+            // The account is online on another device.
+            ("E2620: You are already online.", ErrorKind::DeviceLimit),
+            ("超过最大连接数", ErrorKind::DeviceLimit),
+            ("E2532: 两次认证的间隔太短", ErrorKind::Unknown),
+            ("Request limit exceeded", ErrorKind::Unknown),
+            ("", ErrorKind::Unknown),
+        ];
+
+        for (message, kind) in cases {
+            assert_eq!(ErrorKind::from_message(message), kind, "{}", message);
+        }
+    }
+}
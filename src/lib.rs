@@ -1,5 +1,24 @@
+pub mod backends {
+    mod backend;
+    pub use backend::{create_backend, LoginBackend, SessionInfo};
+
+    #[cfg(feature = "browser")]
+    mod browser;
+    #[cfg(feature = "browser")]
+    pub use browser::BrowserBackend;
+
+    mod srun;
+    pub use srun::SrunBackend;
+}
 pub mod config;
+#[cfg(unix)]
+pub mod control;
+pub mod error;
 pub mod logger;
+pub mod portal;
+pub mod probe;
+pub mod retry;
+pub mod secret;
 pub mod utils;
 
 pub mod tasks {
@@ -7,7 +26,10 @@ pub mod tasks {
     pub use task::{AppEvent, Task};
 
     mod login;
-    pub use login::LoginTask;
+    pub use login::{LoginTask, TaskStatus};
+
+    mod supervisor;
+    pub use supervisor::{Health, RestartPolicy, Supervisor};
 
     #[cfg(windows)]
     mod tray;
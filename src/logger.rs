@@ -1,7 +1,54 @@
+use std::fmt::Arguments;
 use std::io::Write;
+use std::sync::RwLock;
 
 const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3f%:z";
 
+/// What secrets are replaced with in logs.
+pub const REDACTED: &str = "********";
+
+/// Secrets that are scrubbed from every log message, so that they do not leak into log files attached to issues.
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+/// Scrub `secret` from all log messages from now on.
+pub fn redact(secret: &str) {
+    if secret.is_empty() {
+        return;
+    }
+
+    let mut secrets = SECRETS.write().unwrap();
+    if !secrets.iter().any(|s| s == secret) {
+        secrets.push(secret.to_string());
+    }
+}
+
+pub(crate) fn scrub(args: &Arguments) -> String {
+    let mut message = args.to_string();
+    for secret in SECRETS.read().unwrap().iter() {
+        message = scrub_token(&message, secret);
+    }
+    message
+}
+
+/// Replace `secret` where it stands as a whole token, i.e. not next to other letters or digits. A short password like `123` is still scrubbed, while `2023` in a timestamp is left alone.
+fn scrub_token(message: &str, secret: &str) -> String {
+    let mut scrubbed = String::with_capacity(message.len());
+    let mut last = 0;
+    for (start, _) in message.match_indices(secret) {
+        let end = start + secret.len();
+        let before = message[..start].chars().next_back();
+        let after = message[end..].chars().next();
+        if before.is_some_and(char::is_alphanumeric) || after.is_some_and(char::is_alphanumeric) {
+            continue;
+        }
+        scrubbed.push_str(&message[last..start]);
+        scrubbed.push_str(REDACTED);
+        last = end;
+    }
+    scrubbed.push_str(&message[last..]);
+    scrubbed
+}
+
 #[cfg(debug_assertions)] // Debug mode.
 pub fn setup_logger() {
     use log::LevelFilter;
@@ -14,7 +61,7 @@ pub fn setup_logger() {
                 chrono::Local::now().format(TIME_FORMAT),
                 record.level(),
                 record.target(),
-                record.args()
+                scrub(record.args())
             )
         })
         .filter(None, LevelFilter::Debug)
@@ -36,7 +83,7 @@ pub fn setup_logger() {
                 chrono::Local::now().format(TIME_FORMAT),
                 record.level(),
                 record.target(),
-                record.args()
+                scrub(record.args())
             )
         })
         .init();
@@ -71,9 +118,35 @@ pub fn setup_logger() {
                 chrono::Local::now().format(TIME_FORMAT),
                 record.level(),
                 record.target(),
-                record.args()
+                scrub(record.args())
             )
         })
         .target(env_logger::Target::Pipe(target))
         .init();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrub_whole_tokens() {
+        let cases = [
+            ("password=123&ac_id=1", "123", "password=********&ac_id=1"),
+            (
+                "Login as 230000 with 123.",
+                "123",
+                "Login as 230000 with ********.",
+            ),
+            ("2023-01-01T12:30:00", "123", "2023-01-01T12:30:00"),
+            ("12", "12", "********"),
+            ("p@ss! and p@ss!", "p@ss!", "******** and ********"),
+            ("密码 abc 错误", "abc", "密码 ******** 错误"),
+            ("abcd", "abc", "abcd"),
+        ];
+
+        for (message, secret, scrubbed) in cases {
+            assert_eq!(scrub_token(message, secret), scrubbed, "{}", message);
+        }
+    }
+}
@@ -1,114 +1,142 @@
 // Disable console popup on windows
 #![cfg_attr(target_os = "windows", windows_subsystem = "windows")]
 
-use std::sync::mpsc;
-use std::thread;
-
-use xdwlan_login::config::load_config;
-use xdwlan_login::logger::setup_logger;
-use xdwlan_login::tasks::{LoginTask, Task};
-
-/// On Windows, the tray task and the login task run in parallel. The tray task is responsible for showing the tray icon and handling user interactions, while the login task is responsible for checking network connectivity and logging in.
-#[cfg(target_os = "windows")]
-fn run() -> anyhow::Result<()> {
-    use xdwlan_login::tasks::TrayTask;
-
-    log::info!("Start.");
+#[cfg(target_os = "linux")]
+mod cli;
 
-    let (tx_login, rx_login) = mpsc::channel();
-    let (tx_tray, rx_tray) = mpsc::channel();
+use std::process::ExitCode;
 
-    let config = load_config()?;
-    let login_task = LoginTask::new(config.username, config.password);
-    let login_task_handle = thread::spawn(move || login_task.run(tx_tray, rx_login));
+use xdwlan_login::error::{classify, ErrorKind};
+use xdwlan_login::logger::setup_logger;
 
-    TrayTask::new().run(tx_login, rx_tray)?;
-    login_task_handle.join().unwrap()?;
+/// Exit codes of the program, so that scripts can tell what happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(target_os = "windows", allow(dead_code))] // Only the CLI reports every outcome.
+enum Exit {
+    /// Logged in, or the command succeeded.
+    Success = 0,
+    Failure = 1,
+    // 2 is used by clap for invalid arguments.
+    AlreadyOnline = 3,
+    CredentialsRejected = 4,
+    AccountUnavailable = 5,
+    PortalNotFound = 6,
+    TimedOut = 7,
+    BrowserUnavailable = 8,
+    /// `check` found the portal intercepting our traffic.
+    CaptivePortal = 9,
+    /// `check` found neither the internet nor the portal.
+    NoNetwork = 10,
+    AttemptsExhausted = 11,
+}
 
-    Ok(())
+impl Exit {
+    fn from_error(error: &anyhow::Error) -> Self {
+        match classify(error) {
+            ErrorKind::WrongCredentials => Exit::CredentialsRejected,
+            ErrorKind::AccountUnavailable => Exit::AccountUnavailable,
+            ErrorKind::PortalUnreachable => Exit::PortalNotFound,
+            ErrorKind::TimedOut => Exit::TimedOut,
+            ErrorKind::AttemptsExhausted => Exit::AttemptsExhausted,
+            ErrorKind::BrowserFailure => Exit::BrowserUnavailable,
+            ErrorKind::DeviceLimit | ErrorKind::Unknown => Exit::Failure,
+        }
+    }
 }
 
-/// On Linux, the program runs in CLI mode. The login task runs in the thread (if not oneshot) for checking network connectivity and logging in. The main thread is responsible for handling signals and quitting the login task.
-#[cfg(target_os = "linux")]
-fn run() -> anyhow::Result<()> {
-    use std::sync::atomic::{AtomicBool, Ordering};
-    use std::sync::Arc;
-    use std::time::Duration;
-
-    use xdwlan_login::tasks::AppEvent;
-
-    // Parse command line arguments.
-    let args = clap::Command::new("xdwlan-login")
-        .version(env!("CARGO_PKG_VERSION"))
-        .about("Login to Xidian University's wireless network.")
-        .arg(
-            clap::Arg::new("oneshot")
-                .short('o')
-                .long("oneshot")
-                .help("Run once and quit.")
-                .action(clap::ArgAction::SetTrue),
-        )
-        .get_matches();
+/// On Windows, the tray task and the login task run in parallel. The tray task is responsible for showing the tray icon and handling user interactions, while the login task is responsible for checking network connectivity and logging in.
+///
+/// Both tasks report to a router thread, which passes requests from the tray to the login task and reports from the login task to the tray.
+#[cfg(target_os = "windows")]
+fn run() -> anyhow::Result<Exit> {
+    use std::sync::mpsc::{self, Sender};
+    use std::thread::{self, JoinHandle};
 
-    log::info!("Start.");
+    use xdwlan_login::config::{config_path, load_config, Overrides};
+    use xdwlan_login::tasks::{AppEvent, LoginTask, Supervisor, Task, TrayTask};
 
-    let config = load_config()?;
-    let login_task = LoginTask::new(config.username, config.password);
+    log::info!("Start.");
 
-    match args.get_one::<bool>("oneshot") {
-        Some(true) => {
-            log::info!("Running in oneshot mode.");
+    let (tx_main, rx_main) = mpsc::channel();
+    let (tx_tray, rx_tray) = mpsc::channel();
 
-            // Run `login` method directly in oneshot mode.
-            if login_task.is_online() {
-                log::info!("You are online.");
-                return Ok(());
-            }
+    let load = || load_config(&config_path(None), &Overrides::from_env()).and_then(LoginTask::new);
+    // The login task is restarted when it crashes, e.g. when the browser panics.
+    let spawn = |login_task: LoginTask, tx_main: Sender<AppEvent>| {
+        let (tx_login, rx_login) = mpsc::channel();
+        let supervisor = Supervisor::new("login", login_task);
+        let handle: JoinHandle<anyhow::Result<()>> =
+            thread::spawn(move || supervisor.run(tx_main, rx_login));
+        (tx_login, handle)
+    };
+    let join = |handle: JoinHandle<anyhow::Result<()>>| -> anyhow::Result<()> {
+        handle
+            .join()
+            .map_err(|_| anyhow::anyhow!("The login task supervisor panicked."))?
+    };
 
-            loop {
-                login_task.login()?;
+    let (mut tx_login, mut login_task_handle) = spawn(load()?, tx_main.clone());
 
-                // Wait a second for network to be ready.
-                thread::sleep(Duration::from_secs(1));
-                if login_task.is_online() {
-                    log::info!("You are online.");
+    let tx_router = tx_main.clone();
+    let router = thread::spawn(move || {
+        for event in rx_main {
+            match event {
+                AppEvent::Quit => {
+                    let _ = tx_login.send(AppEvent::Quit);
                     break;
                 }
-
-                // Hang up for 5 seconds for next login attempt to avoid being banned.
-                thread::sleep(Duration::from_secs(5));
+                AppEvent::ReloadConfig => match load() {
+                    Ok(login_task) => {
+                        let _ = tx_login.send(AppEvent::Quit);
+                        if let Err(e) = join(login_task_handle) {
+                            log::error!("{}", e);
+                        }
+                        (tx_login, login_task_handle) = spawn(login_task, tx_router.clone());
+                        log::info!("Config reloaded.");
+                    }
+                    Err(e) => log::error!("Failed to reload config: {}", e),
+                },
+                AppEvent::ForceLogin | AppEvent::Logout(_) | AppEvent::Pause | AppEvent::Resume => {
+                    let _ = tx_login.send(event);
+                }
+                AppEvent::StatusChanged(_)
+                | AppEvent::LoginAttempt
+                | AppEvent::LoginSucceeded
+                | AppEvent::LoginFailed(_) => {
+                    let _ = tx_tray.send(event);
+                }
             }
         }
-        _ => {
-            log::info!("Running in daemon mode.");
-
-            let (tx_login, rx_login) = mpsc::channel();
-            let (tx_main, _rx_main) = mpsc::channel();
+        join(login_task_handle)
+    });
+
+    let result = TrayTask::new().run(tx_main.clone(), rx_tray);
+    // The tray sends `Quit` when the user quits, but not when it fails.
+    let _ = tx_main.send(AppEvent::Quit);
+    router
+        .join()
+        .map_err(|_| anyhow::anyhow!("The router thread panicked."))??;
+    result?;
+
+    Ok(Exit::Success)
+}
 
-            // Run `run` method in daemon mode.
-            let login_task_handle = thread::spawn(move || login_task.run(tx_main, rx_login));
+fn main() -> ExitCode {
+    setup_logger();
 
-            let term = Arc::new(AtomicBool::new(false));
-            signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&term))?;
-            signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&term))?;
+    #[cfg(target_os = "linux")]
+    let result = cli::run();
+    #[cfg(target_os = "windows")]
+    let result = run();
 
-            while !term.load(Ordering::Relaxed) {
-                log::debug!("Main task is running.");
-                thread::sleep(Duration::from_secs(1));
-            }
-            tx_login.send(AppEvent::Quit)?;
-            login_task_handle.join().unwrap()?;
+    let exit = match result {
+        Ok(exit) => exit,
+        Err(e) => {
+            log::error!("{}", e);
+            Exit::from_error(&e)
         }
     };
-
-    Ok(())
-}
-
-fn main() {
-    setup_logger();
-
-    if let Err(e) = run() {
-        log::error!("{}", e);
-    }
     log::info!("Quit.");
+
+    ExitCode::from(exit as u8)
 }
@@ -0,0 +1,438 @@
+use std::fmt;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{format_bytes, format_duration};
+
+/// The API is on the local network, so it answers quickly or not at all.
+const API_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a URI discovered from the network is trusted. We do not look it up at every check, but still notice moving to another network.
+const DISCOVERY_TTL: Duration = Duration::from_secs(300);
+
+/// The state reported by a Captive Portal API server (RFC 8908).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct CaptivePortalState {
+    pub captive: bool,
+    pub user_portal_url: Option<String>,
+    pub venue_info_url: Option<String>,
+    pub can_extend_session: Option<bool>,
+    pub seconds_remaining: Option<u64>,
+    pub bytes_remaining: Option<u64>,
+}
+
+impl fmt::Display for CaptivePortalState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Captive:     {}",
+            if self.captive { "yes" } else { "no" }
+        )?;
+        if let Some(seconds) = self.seconds_remaining {
+            write!(f, "\nTime left:   {}", format_duration(seconds))?;
+        }
+        if let Some(bytes) = self.bytes_remaining {
+            write!(f, "\nData left:   {}", format_bytes(bytes))?;
+        }
+        Ok(())
+    }
+}
+
+/// Find out whether the portal intercepts our traffic, and where its login page is.
+///
+/// The standard Captive Portal API is asked first if we know its URI, either from the config or from DHCPv4 (RFC 8910). Otherwise we fall back to looking for the login page in an intercepted response.
+#[derive(Debug, Clone, Default)]
+pub struct PortalDiscovery {
+    api: Option<String>,
+    /// The host of the configured portal. Redirections elsewhere are not taken for the portal unless they land on a login page.
+    portal_host: Option<String>,
+    /// Shared between clones.
+    discovered: Arc<Mutex<Option<Discovered>>>,
+}
+
+/// When we looked up the API URI from the network, and what we found.
+type Discovered = (Instant, Option<String>);
+
+impl PortalDiscovery {
+    /// `api` overrides the Captive Portal API URI advertised by the network. `portal` is the base URL of the portal.
+    pub fn new(api: Option<String>, portal: &str) -> Self {
+        PortalDiscovery {
+            api,
+            portal_host: Url::parse(portal)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string)),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the login url if we are intercepted by the portal.
+    pub fn find_login_url(&self) -> anyhow::Result<Option<String>> {
+        match self.query_api() {
+            Some(Ok(state)) => {
+                log::debug!("Captive Portal API: {:?}", state);
+                if !state.captive {
+                    return Ok(None);
+                }
+                if let Some(url) = state.user_portal_url {
+                    return Ok(Some(url));
+                }
+            }
+            Some(Err(e)) => log::debug!("Captive Portal API failed: {}", e),
+            None => {}
+        }
+
+        self.scrape_login_url()
+    }
+
+    /// Ask the Captive Portal API. Returns `None` if the network does not advertise one.
+    pub fn query_api(&self) -> Option<anyhow::Result<CaptivePortalState>> {
+        let uri = self.api.clone().or_else(|| self.discovered_api_uri())?;
+        Some(query_api(&uri))
+    }
+
+    fn discovered_api_uri(&self) -> Option<String> {
+        let mut discovered = self.discovered.lock().unwrap();
+        if let Some((at, uri)) = &*discovered {
+            if at.elapsed() < DISCOVERY_TTL {
+                return uri.clone();
+            }
+        }
+
+        let uri = discover_api_uri();
+        *discovered = Some((Instant::now(), uri.clone()));
+        uri
+    }
+}
+
+fn query_api(uri: &str) -> anyhow::Result<CaptivePortalState> {
+    let client = reqwest::blocking::ClientBuilder::new()
+        .no_proxy()
+        .timeout(API_TIMEOUT)
+        .build()?;
+    let resp = client
+        .get(uri)
+        .header(reqwest::header::ACCEPT, "application/captive+json")
+        .send()?
+        .error_for_status()?;
+
+    Ok(serde_json::from_str(&resp.text()?)?)
+}
+
+/// Look up the API URI from DHCPv4 option 114, as recorded by NetworkManager or systemd-networkd. The DHCPv6 option and the IPv6 RA option of RFC 8910 are not looked up.
+#[cfg(target_os = "linux")]
+fn discover_api_uri() -> Option<String> {
+    // NetworkManager names the option `captive_portal`, e.g. `DHCP4.OPTION[3]:captive_portal = https://...`.
+    if let Ok(output) = std::process::Command::new("nmcli")
+        .args(["-t", "-f", "DHCP4", "device", "show"])
+        .output()
+    {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        for line in stdout.lines() {
+            if let Some((_, value)) = line.split_once("captive_portal = ") {
+                return Some(value.trim().to_string());
+            }
+        }
+    }
+
+    // systemd-networkd keeps leases as `KEY=value` files.
+    if let Ok(entries) = std::fs::read_dir("/run/systemd/netif/leases") {
+        for entry in entries.flatten() {
+            if let Ok(content) = std::fs::read_to_string(entry.path()) {
+                for line in content.lines() {
+                    if let Some(value) = line.strip_prefix("CAPTIVE_PORTAL=") {
+                        return Some(value.trim().to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Windows does not expose DHCP option 114, so the URI has to be configured.
+#[cfg(not(target_os = "linux"))]
+fn discover_api_uri() -> Option<String> {
+    None
+}
+
+/// Any plain HTTP site works, as long as it is not on the portal's allowlist.
+const INTERCEPT_URL: &str = "http://www.baidu.com";
+
+/// Follow at most this many redirections from the intercepted request to the login page.
+const MAX_HOPS: usize = 5;
+
+impl PortalDiscovery {
+    /// When you were offline, any HTTP request is redirected to the login page. The portal may redirect us with a `Location` header, a `<meta http-equiv="refresh">` tag, a script setting `location.href`, or a form to be submitted, so we follow all of them until we land on a page that does not redirect any further.
+    ///
+    /// The page we end up on is only taken for the login page if it asks for a password, or if it is on the portal's host. Other sites redirect too, e.g. CDNs and anti-bot checks.
+    pub fn scrape_login_url(&self) -> anyhow::Result<Option<String>> {
+        self.scrape_from(Url::parse(INTERCEPT_URL)?)
+    }
+
+    fn scrape_from(&self, start: Url) -> anyhow::Result<Option<String>> {
+        let client = reqwest::blocking::ClientBuilder::new()
+            .no_proxy()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+
+        // Sometimes, the redirection will fail, so we try at most 5 times.
+        for _ in 0..5 {
+            let mut url = start.clone();
+            let mut login_page = false;
+            for _ in 0..MAX_HOPS {
+                let resp = match client.get(url.clone()).send() {
+                    Ok(resp) => resp,
+                    // We may not be able to open the login page itself, e.g. due to certificate errors, but we know where it is.
+                    Err(e) if url != start => {
+                        log::debug!("Failed to follow {}: {}", url, e);
+                        break;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+
+                let location = resp
+                    .headers()
+                    .get(reqwest::header::LOCATION)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string);
+                let next = match location {
+                    Some(location) if resp.status().is_redirection() => url.join(&location).ok(),
+                    _ => {
+                        let content = resp.text()?;
+                        // The login page has redirections too, e.g. to the success page after login.
+                        if is_login_page(&content) {
+                            login_page = true;
+                            break;
+                        }
+                        find_redirect(&url, &content)
+                    }
+                };
+
+                // Redirections within the same host are not what the portal does, e.g. the forms on the search page.
+                match next {
+                    Some(next) if next != url && !(url == start && next.host() == start.host()) => {
+                        log::debug!("Redirected from {} to {}", url, next);
+                        url = next;
+                    }
+                    _ => break,
+                }
+            }
+
+            if url == start {
+                continue;
+            }
+            if login_page || url.host_str() == self.portal_host.as_deref() {
+                return Ok(Some(url.to_string()));
+            }
+            log::debug!("Redirected to {}, which is not the portal.", url);
+            return Ok(None);
+        }
+
+        Ok(None)
+    }
+}
+
+/// The page asking for a password is where we stop.
+fn is_login_page(content: &str) -> bool {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN
+        .get_or_init(|| Regex::new(r#"(?i)<input[^>]+type\s*=\s*["']?password"#).unwrap())
+        .is_match(content)
+}
+
+/// Find where the page redirects us to, with relative urls resolved against `base`.
+pub fn find_redirect(base: &Url, content: &str) -> Option<Url> {
+    static PATTERNS: OnceLock<Vec<Regex>> = OnceLock::new();
+    let patterns = PATTERNS.get_or_init(|| {
+        [
+            // <meta http-equiv="refresh" content="0; url=https://...">
+            r#"(?is)<meta[^>]+http-equiv\s*=\s*["']?refresh["']?[^>]*content\s*=\s*["'][^"']*?url\s*=\s*['"]?(?P<url>[^"'>\s]+)"#,
+            // location.href = "...", window.location = '...', top.location.href="..."
+            r#"(?i)\blocation(?:\.href)?\s*=\s*["'](?P<url>[^"']+)["']"#,
+            // location.replace("..."), location.assign('...')
+            r#"(?i)\blocation\.(?:replace|assign)\(\s*["'](?P<url>[^"']+)["']\s*\)"#,
+            // <form action="...">
+            r#"(?is)<form[^>]+action\s*=\s*["'](?P<url>[^"']+)["']"#,
+        ]
+        .iter()
+        .map(|pattern| Regex::new(pattern).unwrap())
+        .collect()
+    });
+
+    patterns.iter().find_map(|re| {
+        let url = re.captures(content)?.name("url")?.as_str();
+        // Urls in HTML attributes and scripts are escaped.
+        let url = url.replace("&amp;", "&").replace("\\/", "/");
+        base.join(url.trim()).ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{http_response, serve_http};
+
+    fn redirect(base: &str, content: &str) -> Option<String> {
+        find_redirect(&Url::parse(base).unwrap(), content).map(|url| url.to_string())
+    }
+
+    #[test]
+    fn meta_refresh() {
+        let content = r#"<html><head><META http-equiv="refresh" content="0;URL=http://w.xidian.edu.cn/index_1.html?ac_id=1&amp;wlanuserip=10.1.2.3&amp;wlanacname=xd"></head></html>"#;
+        assert_eq!(
+            redirect("http://www.baidu.com/", content).as_deref(),
+            Some("http://w.xidian.edu.cn/index_1.html?ac_id=1&wlanuserip=10.1.2.3&wlanacname=xd")
+        );
+    }
+
+    #[test]
+    fn script_location() {
+        let content = "<script>top.self.location.href='http://w.xidian.edu.cn/srun_portal_pc?ac_id=8&theme=pro'</script>";
+        assert_eq!(
+            redirect("http://www.baidu.com/", content).as_deref(),
+            Some("http://w.xidian.edu.cn/srun_portal_pc?ac_id=8&theme=pro")
+        );
+
+        // JSON-escaped slashes in inline scripts.
+        let content = r#"<script>window.location = "http:\/\/10.255.44.33\/?ac_id=1";</script>"#;
+        assert_eq!(
+            redirect("http://www.baidu.com/", content).as_deref(),
+            Some("http://10.255.44.33/?ac_id=1")
+        );
+    }
+
+    #[test]
+    fn script_location_replace_relative() {
+        let content = r#"<script type="text/javascript">location.replace("/srun_portal_pc?ac_id=1&wlanuserip=10.1.2.3")</script>"#;
+        assert_eq!(
+            redirect("http://w.xidian.edu.cn/index_1.html", content).as_deref(),
+            Some("http://w.xidian.edu.cn/srun_portal_pc?ac_id=1&wlanuserip=10.1.2.3")
+        );
+    }
+
+    #[test]
+    fn form_action_relative() {
+        let content = r#"<body onload="document.f.submit()"><form name="f" method="post" action="./srun_portal_pc?ac_id=1"><input type="hidden" name="userip" value="10.1.2.3"></form></body>"#;
+        assert_eq!(
+            redirect("http://w.xidian.edu.cn/portal/index.html", content).as_deref(),
+            Some("http://w.xidian.edu.cn/portal/srun_portal_pc?ac_id=1")
+        );
+    }
+
+    #[test]
+    fn no_redirect() {
+        assert_eq!(
+            redirect(
+                "http://www.baidu.com/",
+                "<html><body>百度一下</body></html>"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn login_page() {
+        assert!(is_login_page(
+            r#"<input type="password" id="password" placeholder="密码">"#
+        ));
+        assert!(is_login_page("<INPUT name=pwd TYPE=password>"));
+        assert!(!is_login_page(
+            r#"<input type="text" id="username"><input type="hidden" name="password">"#
+        ));
+    }
+
+    /// Serve the routes returned by `routes` on a random port, answering each path with a raw HTTP response. `routes` is given the port, so that responses can redirect to absolute URLs. Returns the port.
+    fn serve(routes: impl FnOnce(u16) -> Vec<(&'static str, String)>) -> u16 {
+        serve_http(|port| {
+            let routes = routes(port);
+            move |target: &str| {
+                let path = target.split('?').next().unwrap();
+                routes
+                    .iter()
+                    .find(|(route, _)| *route == path)
+                    .map(|(_, response)| response.clone())
+                    .unwrap_or_else(|| page("404 Not Found", ""))
+            }
+        })
+    }
+
+    fn page(status: &str, body: &str) -> String {
+        http_response(status, "text/html", body)
+    }
+
+    fn moved(location: &str) -> String {
+        format!(
+            "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            location
+        )
+    }
+
+    fn scrape(portal: &str, port: u16) -> Option<String> {
+        let start = Url::parse(&format!("http://127.0.0.1:{}/", port)).unwrap();
+        PortalDiscovery::new(None, portal)
+            .scrape_from(start)
+            .unwrap()
+    }
+
+    // `127.0.0.1` plays the intercepted site, and `localhost` another host such as the portal.
+
+    #[test]
+    fn scrape_follows_location_and_meta_refresh_to_login_page() {
+        let port = serve(|port| {
+            vec![
+                (
+                    "/",
+                    moved(&format!(
+                        "http://localhost:{}/index_1.html?ac_id=1&wlanuserip=10.1.2.3",
+                        port
+                    )),
+                ),
+                (
+                    "/index_1.html",
+                    page(
+                        "200 OK",
+                        r#"<meta http-equiv="refresh" content="0; url=/srun_portal_pc?ac_id=1&amp;theme=pro">"#,
+                    ),
+                ),
+                (
+                    "/srun_portal_pc",
+                    page("200 OK", r#"<input type="password" id="password">"#),
+                ),
+            ]
+        });
+
+        assert_eq!(
+            scrape("http://portal.invalid", port),
+            Some(format!(
+                "http://localhost:{}/srun_portal_pc?ac_id=1&theme=pro",
+                port
+            ))
+        );
+    }
+
+    #[test]
+    fn scrape_ignores_redirects_off_the_portal() {
+        let port = serve(|port| {
+            vec![
+                ("/", moved(&format!("http://localhost:{}/challenge", port))),
+                (
+                    "/challenge",
+                    page("200 OK", "<p>Checking your browser...</p>"),
+                ),
+            ]
+        });
+
+        assert_eq!(scrape("http://portal.invalid", port), None);
+        // The same redirect is the portal if it goes to the configured host.
+        assert_eq!(
+            scrape("http://localhost", port),
+            Some(format!("http://localhost:{}/challenge", port))
+        );
+    }
+}
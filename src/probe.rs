@@ -0,0 +1,153 @@
+use std::fmt;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::backends::LoginBackend;
+use crate::config::{ConnectivityConfig, Probe, ProbePolicy};
+
+/// What the network looks like from this device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetState {
+    /// The internet is reachable.
+    Online,
+    /// The portal intercepts our traffic, so we need to login.
+    CaptivePortal,
+    /// Neither the internet nor the portal is reachable, e.g. the cable is unplugged.
+    NoNetwork,
+}
+
+impl fmt::Display for NetState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetState::Online => write!(f, "online"),
+            NetState::CaptivePortal => write!(f, "captive portal"),
+            NetState::NoNetwork => write!(f, "no network"),
+        }
+    }
+}
+
+impl Probe {
+    /// Run the probe. It succeeds only if the network behaves as an open internet connection would.
+    pub fn check(&self, timeout: Duration) -> anyhow::Result<()> {
+        match self {
+            Probe::Http204 { url } => {
+                let status = http_client(timeout)?.get(url).send()?.status();
+                if status.as_u16() != 204 {
+                    return Err(anyhow::anyhow!("{} responded {}", url, status));
+                }
+            }
+            Probe::HttpBody { url, contains } => {
+                let resp = http_client(timeout)?.get(url).send()?;
+                let status = resp.status();
+                if status.as_u16() != 200 {
+                    return Err(anyhow::anyhow!("{} responded {}", url, status));
+                }
+                if !resp.text()?.contains(contains.as_str()) {
+                    return Err(anyhow::anyhow!("{} responded unexpected content", url));
+                }
+            }
+            Probe::Tcp { address } => {
+                let addr = address
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("{} has no address", address))?;
+                TcpStream::connect_timeout(&addr, timeout)?;
+            }
+            Probe::Dns { host } => {
+                // The system resolver has no timeout, so we wait for it in another thread.
+                let (tx, rx) = mpsc::channel();
+                let query = format!("{}:0", host);
+                thread::spawn(move || {
+                    let _ = tx.send(query.to_socket_addrs().map(|mut addrs| addrs.next()));
+                });
+                match rx.recv_timeout(timeout) {
+                    Ok(Ok(Some(_))) => {}
+                    Ok(Ok(None)) => return Err(anyhow::anyhow!("{} has no address", host)),
+                    Ok(Err(e)) => return Err(e.into()),
+                    Err(_) => return Err(anyhow::anyhow!("resolving {} timed out", host)),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Redirects are not followed, since being redirected is exactly what a captive portal does.
+fn http_client(timeout: Duration) -> anyhow::Result<reqwest::blocking::Client> {
+    let client = reqwest::blocking::ClientBuilder::new()
+        .no_proxy()
+        .timeout(timeout)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?;
+
+    Ok(client)
+}
+
+/// Run the probes first, and only look for the portal if they fail.
+pub fn classify(config: &ConnectivityConfig, backend: &dyn LoginBackend) -> NetState {
+    detect(config, backend).0
+}
+
+/// Same as `classify`, but also returns the login url when we are intercepted by the portal.
+pub fn detect(
+    config: &ConnectivityConfig,
+    backend: &dyn LoginBackend,
+) -> (NetState, Option<String>) {
+    if is_online(config) {
+        return (NetState::Online, None);
+    }
+
+    match backend.detect_portal() {
+        Ok(Some(url)) => {
+            log::debug!("Intercepted by the portal: {}", url);
+            (NetState::CaptivePortal, Some(url))
+        }
+        Ok(None) => (NetState::NoNetwork, None),
+        Err(e) => {
+            log::debug!("Portal detection failed: {}", e);
+            (NetState::NoNetwork, None)
+        }
+    }
+}
+
+/// Run all probes in parallel and check the results against the policy.
+pub fn is_online(config: &ConnectivityConfig) -> bool {
+    let passed = thread::scope(|s| {
+        let handles: Vec<_> = config
+            .probes
+            .iter()
+            .map(|probe| {
+                s.spawn(move || {
+                    let timeout = Duration::from_secs(probe.timeout.unwrap_or(config.timeout));
+                    let result = probe.probe.check(timeout);
+                    if let Err(e) = &result {
+                        log::debug!("Probe {:?} failed: {}", probe.probe, e);
+                    }
+                    result.is_ok()
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join())
+            .filter(|result| matches!(result, Ok(true)))
+            .count()
+    });
+
+    let total = config.probes.len();
+    let required = match config.policy {
+        ProbePolicy::Any => 1,
+        ProbePolicy::All => total,
+        ProbePolicy::Quorum(n) => n,
+    };
+
+    log::debug!("{}/{} probes passed, {} required.", passed, total, required);
+    passed >= required
+}
@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::config::{RetryPolicy, MAX_RETRY_DELAY};
+
+/// Tracks login attempts and tells how long to wait before the next one.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    policy: RetryPolicy,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Backoff {
+            policy,
+            attempts: 0,
+        }
+    }
+
+    /// Override the maximum number of attempts of the policy.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.policy.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// The number of attempts made so far.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Record an attempt. Returns the delay before the next one, or `None` if we should give up.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        self.attempts += 1;
+        if let Some(max_attempts) = self.policy.max_attempts {
+            if self.attempts >= max_attempts {
+                return None;
+            }
+        }
+
+        let policy = &self.policy;
+        let delay = policy.initial_delay * policy.multiplier.powi(self.attempts as i32 - 1);
+        let delay = delay.min(policy.max_delay);
+
+        let jitter = policy.jitter.clamp(0.0, 1.0);
+        let delay = if jitter > 0.0 {
+            delay * rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter)
+        } else {
+            delay
+        };
+
+        // The policy is validated when the config is loaded, this only guards against other sources.
+        let delay = if delay.is_nan() {
+            0.0
+        } else {
+            delay.clamp(0.0, MAX_RETRY_DELAY)
+        };
+        Some(Duration::from_secs_f64(delay))
+    }
+
+    /// Start over, e.g. after a successful login.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(initial_delay: f64, multiplier: f64, max_delay: f64) -> RetryPolicy {
+        RetryPolicy {
+            initial_delay,
+            multiplier,
+            max_delay,
+            jitter: 0.0,
+            max_attempts: None,
+        }
+    }
+
+    #[test]
+    fn delays_grow_up_to_max_delay() {
+        let mut backoff = Backoff::new(policy(1.0, 2.0, 5.0));
+        let delays: Vec<_> = (0..5).map(|_| backoff.next_delay().unwrap()).collect();
+        assert_eq!(
+            delays,
+            [1.0, 2.0, 4.0, 5.0, 5.0].map(Duration::from_secs_f64)
+        );
+    }
+
+    #[test]
+    fn out_of_range_delays_do_not_panic() {
+        for policy in [
+            policy(1.0, 2.0, 1e300),
+            policy(1.0, 2.0, f64::INFINITY),
+            policy(f64::NAN, 2.0, 10.0),
+            policy(-5.0, 2.0, 10.0),
+        ] {
+            let mut backoff = Backoff::new(policy);
+            for _ in 0..2000 {
+                assert!(backoff.next_delay().unwrap() <= Duration::from_secs_f64(MAX_RETRY_DELAY));
+            }
+        }
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut backoff = Backoff::new(RetryPolicy {
+            max_attempts: Some(3),
+            ..policy(1.0, 2.0, 5.0)
+        });
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_none());
+    }
+}
@@ -0,0 +1,190 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+/// A string that must not end up in logs, such as a password. `Debug` and `Display` print a placeholder, `expose` gives the value.
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Secret(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(\"{}\")", crate::logger::REDACTED)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", crate::logger::REDACTED)
+    }
+}
+
+/// Where the password comes from. It is read again before every login attempt, so a rotated password is picked up without restarting.
+#[derive(Debug, Clone)]
+pub enum PasswordSource {
+    /// Written in the config file, or given by an override.
+    Plain(Secret),
+    /// The first line of a file.
+    File(PathBuf),
+    /// The first line printed by a shell command, e.g. `pass show xidian`.
+    Command(String),
+    /// An item in the Secret Service (GNOME Keyring, KWallet), looked up with `secret-tool`.
+    Keyring { service: String, account: String },
+}
+
+impl PasswordSource {
+    /// Read the password. It is also scrubbed from the logs from now on.
+    pub fn resolve(&self) -> anyhow::Result<Secret> {
+        let password = match self {
+            PasswordSource::Plain(password) => return Ok(password.clone()),
+            PasswordSource::File(path) => std::fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("Failed to read password from {}: {}", self, e))?,
+            PasswordSource::Command(command) => self.run(shell(command))?,
+            PasswordSource::Keyring { service, account } => {
+                let mut command = Command::new("secret-tool");
+                command.args(["lookup", "service", service, "username", account]);
+                self.run(command)?
+            }
+        };
+
+        let password = password.lines().next().unwrap_or_default();
+        if password.is_empty() {
+            return Err(anyhow::anyhow!("The password from {} is empty.", self));
+        }
+        crate::logger::redact(password);
+
+        Ok(Secret::new(password))
+    }
+
+    /// Run `command` and return what it prints.
+    fn run(&self, mut command: Command) -> anyhow::Result<String> {
+        let output = command
+            .output()
+            .map_err(|e| anyhow::anyhow!("Failed to read password from {}: {}", self, e))?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "Failed to read password from {}, {}: {}",
+                self,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        Ok(String::from_utf8(output.stdout)?)
+    }
+}
+
+impl fmt::Display for PasswordSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordSource::Plain(_) => write!(f, "the config"),
+            PasswordSource::File(path) => write!(f, "file {}", path.display()),
+            PasswordSource::Command(command) => write!(f, "command `{}`", command),
+            PasswordSource::Keyring { service, account } => {
+                write!(f, "keyring item {}/{}", service, account)
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.args(["/C", command]);
+    shell
+}
+
+#[cfg(not(target_os = "windows"))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.args(["-c", command]);
+    shell
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str, content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("xdwlan-login-{}-{}", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn file_gives_first_line() {
+        let path = temp_file("password", "from-file\nsecond line\n");
+        let password = PasswordSource::File(path.clone()).resolve();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(password.unwrap().expose(), "from-file");
+    }
+
+    #[test]
+    fn empty_file_is_rejected() {
+        let path = temp_file("empty-password", "\nfrom-second-line\n");
+        let e = PasswordSource::File(path.clone()).resolve().unwrap_err();
+        std::fs::remove_file(path).unwrap();
+
+        assert!(e.to_string().contains("is empty"), "{}", e);
+    }
+
+    #[test]
+    fn missing_file_is_reported() {
+        let path = std::env::temp_dir().join("xdwlan-login-no-such-password");
+        let e = PasswordSource::File(path).resolve().unwrap_err();
+        assert!(e.to_string().contains("Failed to read password"), "{}", e);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn command_gives_first_line() {
+        let source = PasswordSource::Command("printf 'from-command\\nsecond line\\n'".to_string());
+        assert_eq!(source.resolve().unwrap().expose(), "from-command");
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn empty_command_output_is_rejected() {
+        let e = PasswordSource::Command("true".to_string())
+            .resolve()
+            .unwrap_err();
+        assert!(e.to_string().contains("is empty"), "{}", e);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn failing_command_is_reported() {
+        let source = PasswordSource::Command("echo locked >&2; exit 3".to_string());
+        let e = source.resolve().unwrap_err().to_string();
+        assert!(e.contains("exit status: 3"), "{}", e);
+        assert!(e.contains("locked"), "{}", e);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn resolved_password_is_redacted() {
+        let source = PasswordSource::Command("echo redact-me-4711".to_string());
+        source.resolve().unwrap();
+
+        let message = crate::logger::scrub(&format_args!("Login with redact-me-4711."));
+        assert_eq!(message, "Login with ********.");
+    }
+}
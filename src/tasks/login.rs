@@ -1,206 +1,459 @@
-use std::time::Duration;
+use std::cell::Cell;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use headless_chrome::{Browser, LaunchOptionsBuilder};
-use std::sync::mpsc::{Receiver, Sender};
+use serde::{Deserialize, Serialize};
 
 use super::{AppEvent, Task};
+use crate::backends::{create_backend, LoginBackend, SessionInfo};
+use crate::config::{Config, ConnectivityConfig, RetryPolicy};
+use crate::error::{classify, ErrorKind};
+use crate::probe::{self, NetState};
+use crate::retry::Backoff;
+use crate::secret::PasswordSource;
 
 pub struct LoginTask {
+    /// Accounts in the order they are tried.
+    accounts: Vec<Credentials>,
+    /// Index of the account in use.
+    current: Cell<usize>,
+    connectivity: ConnectivityConfig,
+    check_interval: Duration,
+    retry: RetryPolicy,
+    backend: Box<dyn LoginBackend>,
+    /// Shared with whoever wants to know what the login loop is doing, e.g. the control socket.
+    status: Arc<Mutex<TaskStatus>>,
+}
+
+/// What the login loop is doing.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TaskStatus {
+    /// `None` until the first check.
+    pub state: Option<NetState>,
+    /// The username of the account in use.
+    pub account: String,
+    /// Set by `Pause` and `Logout`, cleared by `Resume`.
+    pub paused: bool,
+    /// Set when the portal rejects our credentials, cleared by `ForceLogin`.
+    pub locked_out: bool,
+    /// The error of the last failed login, cleared once we are online.
+    pub last_error: Option<String>,
+}
+
+struct Credentials {
     username: String,
-    password: String,
+    password: PasswordSource,
 }
 
 impl LoginTask {
-    pub fn new(username: String, password: String) -> Self {
-        LoginTask { username, password }
-    }
-
-    pub fn is_online(&self) -> bool {
-        let client = reqwest::blocking::ClientBuilder::new()
-            .no_proxy()
-            .build()
-            .unwrap(); // This method only panics if called from within an async runtime.
-        if let Ok(resp) = client.get("http://wifi.vivo.com.cn/generate_204").send() {
-            if resp.status().as_u16() == 204 {
-                return true;
-            } else {
-                return false;
-            }
-        } else {
-            return false;
-        }
+    /// Create a login task with the backend selected in the config.
+    pub fn new(config: Config) -> anyhow::Result<Self> {
+        let backend = create_backend(&config)?;
+
+        Self::with_backend(config, backend)
     }
 
-    fn get_login_url(&self) -> anyhow::Result<String> {
-        let client = reqwest::blocking::ClientBuilder::new().no_proxy().build()?;
-
-        // When you were offline, you will be redirct to the login page.
-        // Sometimes, the redirection will fail, so we try at most 5 times.
-        for _ in 0..5 {
-            let resp = client.get("http://www.baidu.com").send()?;
-            let content = resp.text()?;
-            if content.contains("w.xidian.edu.cn") {
-                let re = regex::Regex::new(
-                    r#"(?m)action="(?P<url>https://w\.xidian\.edu\.cn[a-zA-Z0-9./_]+)""#,
-                )?;
-                if let Some(cap) = re.captures(&content) {
-                    return Ok(cap["url"].to_string());
-                }
-            }
-        }
+    pub fn with_backend(config: Config, backend: Box<dyn LoginBackend>) -> anyhow::Result<Self> {
+        let accounts = config
+            .accounts()?
+            .into_iter()
+            .map(|account| {
+                Ok(Credentials {
+                    password: account.password_source()?,
+                    username: account.username,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let status = TaskStatus {
+            account: accounts[0].username.clone(),
+            ..Default::default()
+        };
 
-        Err(anyhow::anyhow!("Login url not found.").into())
+        Ok(LoginTask {
+            accounts,
+            current: Cell::new(0),
+            status: Arc::new(Mutex::new(status)),
+            connectivity: config.connectivity,
+            check_interval: Duration::from_secs(config.check_interval),
+            retry: config.retry,
+            backend,
+        })
     }
 
-    // In debug mode, we disable headless mode to see what's happening.
-    #[cfg(debug_assertions)]
-    fn create_browser(&self) -> anyhow::Result<Browser> {
-        let browser = Browser::new(LaunchOptionsBuilder::default().headless(false).build()?)?;
+    /// A handle to the status of the login loop, which stays valid while the task runs in another thread.
+    pub fn status_handle(&self) -> Arc<Mutex<TaskStatus>> {
+        Arc::clone(&self.status)
+    }
 
-        Ok(browser)
+    fn status_snapshot(&self) -> TaskStatus {
+        self.status.lock().unwrap().clone()
     }
 
-    #[cfg(not(debug_assertions))]
-    fn create_browser(&self) -> anyhow::Result<Browser> {
-        use std::env::temp_dir;
+    fn update_status(&self, f: impl FnOnce(&mut TaskStatus)) {
+        f(&mut self.status.lock().unwrap());
+    }
 
-        let user_data_dir = temp_dir().join("xdwlan-login");
+    /// A fresh backoff for a series of login attempts.
+    pub fn backoff(&self) -> Backoff {
+        Backoff::new(self.retry.clone())
+    }
 
-        if !user_data_dir.exists() {
-            std::fs::create_dir(&user_data_dir)?;
-            log::info!("User data dir: {}", user_data_dir.display());
-        }
+    /// The name of the backend in use.
+    pub fn backend_name(&self) -> &'static str {
+        self.backend.name()
+    }
 
-        let browser = Browser::new(
-            LaunchOptionsBuilder::default()
-                .user_data_dir(Some(user_data_dir))
-                .build()?,
-        )?;
+    /// Tell whether we are online, intercepted by the portal, or not connected at all.
+    pub fn check_network(&self) -> NetState {
+        probe::classify(&self.connectivity, self.backend.as_ref())
+    }
 
-        Ok(browser)
+    /// Same as `check_network`, but also returns the login url when we are intercepted by the portal.
+    pub fn detect_network(&self) -> (NetState, Option<String>) {
+        probe::detect(&self.connectivity, self.backend.as_ref())
     }
 
-    /// Open a browser and login to the network.
-    pub fn login(&self) -> anyhow::Result<()> {
-        let url = self.get_login_url()?;
-        log::info!("Got login url: {}", url);
-
-        // Create a browser and a new tab.
-        let browser = self.create_browser()?;
-        let tab = browser.new_tab()?;
-
-        // Navigate to the login page. Try at most 5 times.
-        for i in 0..5 {
-            match tab.navigate_to(&url) {
-                Ok(_) => {
-                    break;
-                }
-                Err(e) => {
-                    log::debug!("Navigate Error: {}", e);
+    /// The username of the account in use.
+    pub fn account(&self) -> &str {
+        &self.accounts[self.current.get()].username
+    }
 
-                    if i == 4 {
-                        return Err(anyhow::anyhow!("Navigate failed for 5 times."));
-                    }
+    /// Login to the network with the configured backend.
+    ///
+    /// If the account in use hits its device limit or runs out of balance, the next account is tried, until every account has been tried once. The account that works is kept for later logins.
+    pub fn login(&self) -> anyhow::Result<()> {
+        let mut result = self.login_as(&self.accounts[self.current.get()]);
+
+        for _ in 1..self.accounts.len() {
+            let e = match &result {
+                Err(e)
+                    if matches!(
+                        classify(e),
+                        ErrorKind::DeviceLimit | ErrorKind::AccountUnavailable
+                    ) =>
+                {
+                    e
                 }
-            }
-        }
-        tab.wait_until_navigated()?;
-
-        // We check the title of the page to determine whether we are redirected to the login page.
-        let url = tab.get_url();
-        if url.contains("w.xidian.edu.cn") {
-            log::debug!("You are redirected to the login page {}", url);
-
-            // Sometimes, the page will show a dialog says "Net Error".
-            // Actually I don't know why, just reload the page to avoid it.
-            // Page has to be reload with cache, otherwise it will always complain "Net Error".
-            tab.reload(false, None)?;
-            tab.wait_until_navigated()?;
-
-            // We try to login here.
-            log::info!("Try to login...");
-            let body = tab.wait_for_element("body")?;
-            body.call_js_fn(
-                r#"function login() {
-                    if (document.querySelector('div.control > button.btn-confirm')) {
-                        document.querySelector('div.control > button.btn-confirm').click();
-                    }
-                    document.querySelector('#username').value = 'username_placeholder';
-                    document.querySelector('#password').value = 'password_placeholder';
-                    document.querySelector('#login-account').click();
-                }"#
-                .replace("username_placeholder", &self.username)
-                .replace("password_placeholder", &self.password)
-                .as_str(),
-                vec![],
-                false,
-            )?;
-        } else {
-            log::error!("Unknown login url: {}", url);
+                _ => break,
+            };
+
+            let next = (self.current.get() + 1) % self.accounts.len();
+            log::warn!(
+                "Account {} can not login: {}. Switch to account {}.",
+                self.account(),
+                e,
+                self.accounts[next].username
+            );
+            self.current.set(next);
+            self.update_status(|status| status.account = self.account().to_string());
+            result = self.login_as(&self.accounts[next]);
         }
 
-        Ok(())
+        result
+    }
+
+    /// The password is read again before every login, in case it has changed.
+    fn login_as(&self, account: &Credentials) -> anyhow::Result<()> {
+        let password = account.password.resolve()?;
+        self.backend.login(&account.username, password.expose())
+    }
+
+    /// Query the portal for the session of this device. Returns `None` if this device is not logged in.
+    pub fn status(&self) -> anyhow::Result<Option<SessionInfo>> {
+        self.backend.query_session()
+    }
+
+    /// Logout from the portal, so that this device is no longer online with our account.
+    pub fn logout(&self) -> anyhow::Result<()> {
+        self.backend.logout(self.account())
     }
 }
 
 impl Task for LoginTask {
-    fn run(&self, _sender: Sender<AppEvent>, receiver: Receiver<AppEvent>) -> anyhow::Result<()> {
-        log::debug!("Login task started.");
-        log::debug!(
-            "Use username: {} and password: {}",
-            self.username,
-            self.password
-        );
-
-        // Sleep seconds and wake up when receive a message.
-        let should_quit = |seconds: u64| {
-            if let Ok(AppEvent::Quit) = receiver.recv_timeout(Duration::from_secs(seconds)) {
-                return true;
-            } else {
-                return false;
+    fn run(&self, sender: Sender<AppEvent>, receiver: Receiver<AppEvent>) -> anyhow::Result<()> {
+        log::debug!("Login task started with {} backend.", self.backend.name());
+        for account in &self.accounts {
+            log::debug!(
+                "Use username: {} and password from {}",
+                account.username,
+                account.password
+            );
+
+            // Fail early if the password can not be read, rather than at the first login.
+            account.password.resolve()?;
+        }
+
+        // Nobody may be listening, which is fine.
+        let notify = |event: AppEvent| {
+            let _ = sender.send(event);
+        };
+
+        // Sleep and handle messages meanwhile. Returns early with `Quit`, `ForceLogin` or `Resume`, which need the loop to act right away.
+        let wait = |timeout: Duration| {
+            let deadline = Instant::now() + timeout;
+            loop {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                match receiver.recv_timeout(timeout) {
+                    Ok(AppEvent::Pause) => {
+                        log::info!("Paused, stop logging in until resumed.");
+                        self.update_status(|status| status.paused = true);
+                    }
+                    Ok(AppEvent::Resume) => {
+                        log::info!("Resumed.");
+                        self.update_status(|status| status.paused = false);
+                        return Some(AppEvent::Resume);
+                    }
+                    Ok(AppEvent::Logout(reply)) => {
+                        // Pause as well, otherwise we would login again at the next check.
+                        self.update_status(|status| status.paused = true);
+                        let result = self.logout();
+                        match &result {
+                            Ok(()) => log::info!("Logout successfully, paused until resumed."),
+                            Err(e) => log::error!("Logout failed: {}", e),
+                        }
+                        if let Some(reply) = reply {
+                            let _ = reply.send(result);
+                        }
+                    }
+                    // The login task can not replace itself, whoever created it has to.
+                    Ok(AppEvent::ReloadConfig) => {
+                        log::debug!("The login task can not reload the config, ignore it.");
+                    }
+                    Ok(event) => return Some(event),
+                    Err(RecvTimeoutError::Timeout) => return None,
+                    // Nobody can tell us to quit any more.
+                    Err(RecvTimeoutError::Disconnected) => return Some(AppEvent::Quit),
+                }
             }
         };
 
+        // Keep logging in while the portal intercepts our traffic. Returns the new network state, or `None` if we should quit.
         let simulate = || {
+            let status = self.status_snapshot();
+            // After the portal rejects our credentials, we stop logging in since retrying can only get the account locked.
+            if status.paused || status.locked_out {
+                return Some(NetState::CaptivePortal);
+            }
+
             log::info!("You are offline now.");
 
+            let mut backoff = self.backoff();
             loop {
+                notify(AppEvent::LoginAttempt);
                 if let Err(e) = self.login() {
+                    let kind = classify(&e);
                     log::error!("{}", e);
+                    notify(AppEvent::LoginFailed(kind));
+                    self.update_status(|status| status.last_error = Some(e.to_string()));
+
+                    if !kind.is_retryable() {
+                        log::error!(
+                            "Login failed: {}. Stop retrying, please check your account and restart the program.",
+                            kind
+                        );
+                        self.update_status(|status| status.locked_out = true);
+                        return Some(NetState::CaptivePortal);
+                    }
                 }
 
                 // Wait a second for network to be ready.
-                if should_quit(1) {
-                    return;
+                if let Some(AppEvent::Quit) = wait(Duration::from_secs(1)) {
+                    return None;
                 }
 
-                if self.is_online() {
-                    log::info!("Login successfully.");
-                    break;
+                match self.check_network() {
+                    NetState::Online => {
+                        log::info!("Login successfully as {}.", self.account());
+                        notify(AppEvent::LoginSucceeded);
+                        self.update_status(|status| status.last_error = None);
+                        return Some(NetState::Online);
+                    }
+                    // There is no point to login if the portal is unreachable.
+                    NetState::NoNetwork => {
+                        log::info!("Network is down, wait for it to come back.");
+                        return Some(NetState::NoNetwork);
+                    }
+                    NetState::CaptivePortal => {}
                 }
 
-                // Hang up for 5 seconds for next login attempt to avoid being banned.
-                if should_quit(5) {
-                    return;
+                // Hang up for next login attempt to avoid being banned.
+                match backoff.next_delay() {
+                    Some(delay) => {
+                        log::debug!("Retry in {:.1} seconds.", delay.as_secs_f64());
+                        // A login requested by the user cuts the delay short.
+                        if let Some(AppEvent::Quit) = wait(delay) {
+                            return None;
+                        }
+                        if self.status_snapshot().paused {
+                            return Some(NetState::CaptivePortal);
+                        }
+                    }
+                    None => {
+                        log::error!(
+                            "Login failed for {} times, try again in the next check.",
+                            backoff.attempts()
+                        );
+                        return Some(NetState::CaptivePortal);
+                    }
                 }
             }
         };
 
         // Check the network status at first.
-        if self.is_online() {
-            log::info!("You are already online.");
-        } else {
-            simulate();
+        let mut state = self.check_network();
+        self.update_status(|status| status.state = Some(state));
+        notify(AppEvent::StatusChanged(state));
+        match state {
+            NetState::Online => log::info!("You are already online."),
+            NetState::NoNetwork => log::info!("Network is down, wait for it to come back."),
+            NetState::CaptivePortal => match simulate() {
+                Some(new_state) if new_state != state => {
+                    state = new_state;
+                    self.update_status(|status| status.state = Some(state));
+                    notify(AppEvent::StatusChanged(state));
+                }
+                Some(_) => {}
+                None => return Ok(()),
+            },
         }
 
         loop {
-            if should_quit(60) {
-                return Ok(());
+            match wait(self.check_interval) {
+                Some(AppEvent::Quit) => return Ok(()),
+                Some(AppEvent::ForceLogin) => {
+                    log::info!("Login requested.");
+                    self.update_status(|status| status.locked_out = false);
+                }
+                _ => {}
+            }
+
+            let new_state = match self.check_network() {
+                NetState::CaptivePortal => match simulate() {
+                    Some(new_state) => new_state,
+                    None => return Ok(()),
+                },
+                new_state => new_state,
+            };
+            if new_state != state {
+                log::info!("Network state changed: {} -> {}.", state, new_state);
+                state = new_state;
+                self.update_status(|status| status.state = Some(state));
+                notify(AppEvent::StatusChanged(state));
             }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::LoginError;
+
+    /// Fails the login of the accounts in `errors` with the given kind, lets the others in, and records who tried.
+    struct FakePortal {
+        errors: Vec<(&'static str, ErrorKind)>,
+        tried: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl LoginBackend for FakePortal {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn detect_portal(&self) -> anyhow::Result<Option<String>> {
+            Ok(None)
+        }
 
-            if !self.is_online() {
-                simulate();
+        fn login(&self, username: &str, _password: &str) -> anyhow::Result<()> {
+            self.tried.lock().unwrap().push(username.to_string());
+            match self.errors.iter().find(|(name, _)| *name == username) {
+                Some((_, kind)) => {
+                    Err(LoginError::new(*kind, format!("{} is refused.", username)).into())
+                }
+                None => Ok(()),
             }
         }
     }
+
+    /// A login task with the accounts `a`, `b` and `c`.
+    fn task(errors: &[(&'static str, ErrorKind)]) -> (LoginTask, Arc<Mutex<Vec<String>>>) {
+        let config: Config = serde_yaml::from_str(
+            "accounts:
+  - username: a
+    password: x
+  - username: b
+    password: x
+  - username: c
+    password: x",
+        )
+        .unwrap();
+        let tried = Arc::new(Mutex::new(Vec::new()));
+        let backend = FakePortal {
+            errors: errors.to_vec(),
+            tried: Arc::clone(&tried),
+        };
+
+        (
+            LoginTask::with_backend(config, Box::new(backend)).unwrap(),
+            tried,
+        )
+    }
+
+    #[test]
+    fn switches_on_device_limit() {
+        let (task, tried) = task(&[("a", ErrorKind::DeviceLimit)]);
+
+        assert!(task.login().is_ok());
+        assert_eq!(*tried.lock().unwrap(), ["a", "b"]);
+        assert_eq!(task.account(), "b");
+        assert_eq!(task.status_snapshot().account, "b");
+    }
+
+    #[test]
+    fn switches_on_account_unavailable() {
+        let (task, tried) = task(&[
+            ("a", ErrorKind::AccountUnavailable),
+            ("b", ErrorKind::DeviceLimit),
+        ]);
+
+        assert!(task.login().is_ok());
+        assert_eq!(*tried.lock().unwrap(), ["a", "b", "c"]);
+        assert_eq!(task.status_snapshot().account, "c");
+    }
+
+    #[test]
+    fn stops_on_wrong_credentials() {
+        let (task, tried) = task(&[("a", ErrorKind::WrongCredentials)]);
+
+        let e = task.login().unwrap_err();
+        assert_eq!(classify(&e), ErrorKind::WrongCredentials);
+        assert_eq!(*tried.lock().unwrap(), ["a"]);
+        assert_eq!(task.account(), "a");
+    }
+
+    #[test]
+    fn keeps_the_account_that_works() {
+        let (task, tried) = task(&[("a", ErrorKind::DeviceLimit)]);
+
+        task.login().unwrap();
+        task.login().unwrap();
+        assert_eq!(*tried.lock().unwrap(), ["a", "b", "b"]);
+    }
+
+    #[test]
+    fn wraps_around_and_tries_each_account_once() {
+        let (task, tried) = task(&[
+            ("a", ErrorKind::DeviceLimit),
+            ("b", ErrorKind::DeviceLimit),
+            ("c", ErrorKind::AccountUnavailable),
+        ]);
+        task.current.set(1);
+
+        let e = task.login().unwrap_err();
+        assert_eq!(classify(&e), ErrorKind::DeviceLimit);
+        assert_eq!(*tried.lock().unwrap(), ["b", "c", "a"]);
+        assert_eq!(task.status_snapshot().account, "a");
+    }
 }
@@ -0,0 +1,385 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use super::{AppEvent, Task};
+use crate::config::RetryPolicy;
+use crate::retry::Backoff;
+
+/// A task that keeps running this long is considered healthy again, so a crash after that starts the delays over.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// When to start a task again after it stops without being told to quit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    Never,
+    /// Restart after the task panics or returns an error.
+    OnFailure,
+    /// Restart whenever the task returns, even successfully.
+    Always,
+}
+
+/// How a supervised task has been doing.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Health {
+    /// The number of times the task has been started again.
+    pub restarts: u32,
+    /// Why the task stopped the last time it crashed.
+    pub last_crash: Option<String>,
+}
+
+/// Runs a task and starts it again according to its `RestartPolicy`, waiting longer after each restart in a row.
+///
+/// The supervisor is a task itself: events it receives are passed on to the task, and `Quit` stops the task for good.
+pub struct Supervisor<T> {
+    name: &'static str,
+    task: T,
+    policy: RestartPolicy,
+    backoff: RetryPolicy,
+    health: Arc<Mutex<Health>>,
+}
+
+impl<T: Task> Supervisor<T> {
+    /// Restart `task` on failure, after 1 second at first and at most 1 minute later on.
+    pub fn new(name: &'static str, task: T) -> Self {
+        Supervisor {
+            name,
+            task,
+            policy: RestartPolicy::OnFailure,
+            backoff: RetryPolicy {
+                initial_delay: 1.0,
+                max_delay: 60.0,
+                ..Default::default()
+            },
+            health: Arc::new(Mutex::new(Health::default())),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: RestartPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// The delays between restarts. With `max_attempts`, the supervisor gives up after that many restarts in a row.
+    pub fn with_backoff(mut self, backoff: RetryPolicy) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// The supervised task, e.g. to get a handle to its status before it runs.
+    pub fn task(&self) -> &T {
+        &self.task
+    }
+
+    /// A handle to the health of the task, which stays valid while the supervisor runs in another thread.
+    pub fn health_handle(&self) -> Arc<Mutex<Health>> {
+        Arc::clone(&self.health)
+    }
+}
+
+impl<T: Task> Task for Supervisor<T> {
+    fn run(&self, sender: Sender<AppEvent>, receiver: Receiver<AppEvent>) -> anyhow::Result<()> {
+        // The task gets a new channel for every run, since a crashed run takes its receiver along.
+        let current: Arc<Mutex<Option<Sender<AppEvent>>>> = Arc::new(Mutex::new(None));
+        let (tx_stop, rx_stop) = mpsc::channel();
+
+        let forwarder = Arc::clone(&current);
+        thread::spawn(move || {
+            for event in &receiver {
+                if let AppEvent::Quit = event {
+                    break;
+                }
+                if let Some(tx) = forwarder.lock().unwrap().as_ref() {
+                    let _ = tx.send(event);
+                }
+            }
+            // Raise the flag first, so that the supervisor does not take the task returning as a crash.
+            let _ = tx_stop.send(());
+            if let Some(tx) = forwarder.lock().unwrap().take() {
+                let _ = tx.send(AppEvent::Quit);
+            }
+        });
+
+        let stopped = || !matches!(rx_stop.try_recv(), Err(TryRecvError::Empty));
+
+        let mut backoff = Backoff::new(self.backoff.clone());
+        loop {
+            let (tx_task, rx_task) = mpsc::channel();
+            *current.lock().unwrap() = Some(tx_task);
+            // Checked after the sender is in place, otherwise a `Quit` in between would be lost.
+            if stopped() {
+                return Ok(());
+            }
+
+            let started = Instant::now();
+            let result =
+                panic::catch_unwind(AssertUnwindSafe(|| self.task.run(sender.clone(), rx_task)));
+            current.lock().unwrap().take();
+
+            if stopped() {
+                log::debug!("The {} task stopped.", self.name);
+                return Ok(());
+            }
+
+            let crash = match result {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => Some(e.to_string()),
+                Err(payload) => Some(format!("panicked: {}", panic_message(payload.as_ref()))),
+            };
+            match (crash, self.policy) {
+                (None, RestartPolicy::Never | RestartPolicy::OnFailure) => return Ok(()),
+                (Some(reason), RestartPolicy::Never) => {
+                    self.health.lock().unwrap().last_crash = Some(reason.clone());
+                    return Err(anyhow::anyhow!(
+                        "The {} task crashed: {}",
+                        self.name,
+                        reason
+                    ));
+                }
+                (None, RestartPolicy::Always) => {
+                    log::warn!("The {} task stopped unexpectedly.", self.name)
+                }
+                (Some(reason), _) => {
+                    log::error!("The {} task crashed: {}", self.name, reason);
+                    self.health.lock().unwrap().last_crash = Some(reason);
+                }
+            }
+
+            if started.elapsed() >= STABLE_AFTER {
+                backoff.reset();
+            }
+            let delay = backoff.next_delay().ok_or_else(|| {
+                anyhow::anyhow!(
+                    "The {} task stopped {} times in a row, give up.",
+                    self.name,
+                    backoff.attempts()
+                )
+            })?;
+            log::info!(
+                "Restart the {} task in {:.1} seconds.",
+                self.name,
+                delay.as_secs_f64()
+            );
+            if rx_stop.recv_timeout(delay) != Err(RecvTimeoutError::Timeout) {
+                return Ok(());
+            }
+            self.health.lock().unwrap().restarts += 1;
+        }
+    }
+}
+
+/// Panics carry the message as either `&str` or `String`.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown reason"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    /// Crashes on the first `crashes` runs, either by panicking or by returning an error, then waits for `Quit`, or returns right away if it `finishes`.
+    struct Flaky {
+        crashes: u32,
+        panics: bool,
+        finishes: bool,
+        runs: Arc<AtomicU32>,
+    }
+
+    impl Flaky {
+        fn new(crashes: u32, panics: bool) -> Self {
+            Flaky {
+                crashes,
+                panics,
+                finishes: false,
+                runs: Arc::new(AtomicU32::new(0)),
+            }
+        }
+
+        fn finishing() -> Self {
+            Flaky {
+                finishes: true,
+                ..Flaky::new(0, false)
+            }
+        }
+    }
+
+    impl Task for Flaky {
+        fn run(&self, _: Sender<AppEvent>, receiver: Receiver<AppEvent>) -> anyhow::Result<()> {
+            if self.runs.fetch_add(1, Ordering::SeqCst) < self.crashes {
+                if self.panics {
+                    panic!("boom");
+                }
+                return Err(anyhow::anyhow!("broken"));
+            }
+            if self.finishes {
+                return Ok(());
+            }
+            for event in receiver {
+                if let AppEvent::Quit = event {
+                    break;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn backoff(initial_delay: f64) -> RetryPolicy {
+        RetryPolicy {
+            initial_delay,
+            max_delay: initial_delay,
+            jitter: 0.0,
+            ..Default::default()
+        }
+    }
+
+    fn supervise(task: Flaky, initial_delay: f64) -> Supervisor<Flaky> {
+        Supervisor::new("test", task).with_backoff(backoff(initial_delay))
+    }
+
+    /// The supervisor is handed back after it returns, to look at its task.
+    type Handle = thread::JoinHandle<(Supervisor<Flaky>, anyhow::Result<()>)>;
+
+    /// Run `supervisor` in another thread, and return the channel to it along with its handle.
+    fn spawn(supervisor: Supervisor<Flaky>) -> (Sender<AppEvent>, Handle) {
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let (sender, _receiver) = mpsc::channel();
+            let result = supervisor.run(sender, rx);
+            (supervisor, result)
+        });
+        (tx, handle)
+    }
+
+    fn wait_for(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn restarts_after_panic() {
+        let supervisor = supervise(Flaky::new(2, true), 0.01);
+        let health = supervisor.health_handle();
+        let (tx, handle) = spawn(supervisor);
+
+        wait_for(|| health.lock().unwrap().restarts == 2);
+        tx.send(AppEvent::Quit).unwrap();
+        let (supervisor, result) = handle.join().unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(supervisor.task().runs.load(Ordering::SeqCst), 3);
+        let health = health.lock().unwrap();
+        assert_eq!(health.last_crash.as_deref(), Some("panicked: boom"));
+    }
+
+    #[test]
+    fn restarts_after_error() {
+        let supervisor = supervise(Flaky::new(1, false), 0.01);
+        let health = supervisor.health_handle();
+        let (tx, handle) = spawn(supervisor);
+
+        wait_for(|| health.lock().unwrap().restarts == 1);
+        tx.send(AppEvent::Quit).unwrap();
+        let (_, result) = handle.join().unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(health.lock().unwrap().last_crash.as_deref(), Some("broken"));
+    }
+
+    #[test]
+    fn quit_stops_without_restart() {
+        let supervisor = supervise(Flaky::new(0, false), 0.01);
+        let runs = Arc::clone(&supervisor.task().runs);
+        let (tx, handle) = spawn(supervisor);
+
+        wait_for(|| runs.load(Ordering::SeqCst) == 1);
+        tx.send(AppEvent::Quit).unwrap();
+        let (supervisor, result) = handle.join().unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(supervisor.task().runs.load(Ordering::SeqCst), 1);
+        assert_eq!(supervisor.health_handle().lock().unwrap().restarts, 0);
+    }
+
+    #[test]
+    fn quit_interrupts_backoff() {
+        let supervisor = supervise(Flaky::new(1, false), 60.0);
+        let health = supervisor.health_handle();
+        let (tx, handle) = spawn(supervisor);
+
+        let started = Instant::now();
+        wait_for(|| health.lock().unwrap().last_crash.is_some());
+        tx.send(AppEvent::Quit).unwrap();
+        let (supervisor, result) = handle.join().unwrap();
+
+        assert!(result.is_ok());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(supervisor.task().runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let supervisor =
+            Supervisor::new("test", Flaky::new(u32::MAX, false)).with_backoff(RetryPolicy {
+                max_attempts: Some(2),
+                ..backoff(0.01)
+            });
+        let (_tx, handle) = spawn(supervisor);
+
+        let (supervisor, result) = handle.join().unwrap();
+
+        assert!(result.unwrap_err().to_string().contains("give up"));
+        assert_eq!(supervisor.task().runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn never_restarts() {
+        let supervisor = supervise(Flaky::new(1, false), 0.01).with_policy(RestartPolicy::Never);
+        let (_tx, handle) = spawn(supervisor);
+
+        let (supervisor, result) = handle.join().unwrap();
+
+        assert!(result.unwrap_err().to_string().contains("crashed: broken"));
+        assert_eq!(supervisor.task().runs.load(Ordering::SeqCst), 1);
+        assert_eq!(supervisor.health_handle().lock().unwrap().restarts, 0);
+    }
+
+    #[test]
+    fn on_failure_stops_after_success() {
+        let (_tx, handle) = spawn(supervise(Flaky::finishing(), 0.01));
+
+        let (supervisor, result) = handle.join().unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(supervisor.task().runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn always_restarts_after_success() {
+        let supervisor = supervise(Flaky::finishing(), 0.01).with_policy(RestartPolicy::Always);
+        let health = supervisor.health_handle();
+        let (tx, handle) = spawn(supervisor);
+
+        wait_for(|| health.lock().unwrap().restarts >= 2);
+        tx.send(AppEvent::Quit).unwrap();
+        let (_, result) = handle.join().unwrap();
+
+        assert!(result.is_ok());
+        assert!(health.lock().unwrap().last_crash.is_none());
+    }
+}
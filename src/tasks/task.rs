@@ -1,13 +1,35 @@
 use std::sync::mpsc::{Receiver, Sender};
 
-#[derive(Clone)]
+use crate::error::ErrorKind;
+use crate::probe::NetState;
+
+#[derive(Debug, Clone)]
 pub enum AppEvent {
     Quit,
+
+    // Reports from the login task, so that UIs can show what it is doing.
+    /// The network state is found different from the last check.
+    StatusChanged(NetState),
+    LoginAttempt,
+    /// We are online after logging in.
+    LoginSucceeded,
+    LoginFailed(ErrorKind),
+
+    // Requests from the user, e.g. through the tray menu or the control socket.
+    /// Login right away instead of waiting for the next check, even after the portal rejected our credentials.
+    ForceLogin,
+    /// Logout from the portal, and pause so that we do not login again. The result is sent back through the channel, if any.
+    Logout(Option<Sender<anyhow::Result<()>>>),
+    /// Stop logging in until `Resume`.
+    Pause,
+    Resume,
+    /// Load the config file again. It is handled by whoever created the login task, which replaces it with a new one. The login task itself ignores it.
+    ReloadConfig,
 }
 
 pub trait Task {
     /// Every task communicates with a daemon or manager through a pair of channels. It uses `notifier` to send events to the daemon or manager, and uses `receiver` to receive events from the daemon or manager.
     ///
-    /// For now, the only event is `Quit`, which means the daemon or manager should quit.
+    /// `Quit` means the task should return. Tasks ignore the events they have no use for.
     fn run(&self, notifier: Sender<AppEvent>, receiver: Receiver<AppEvent>) -> anyhow::Result<()>;
 }
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::sync::mpsc::{Receiver, Sender};
+use std::time::Duration;
 use tray_icon::TrayIcon;
 use tray_icon::{
     menu::{
@@ -10,13 +11,17 @@ use tray_icon::{
 use winit::{
     application::ApplicationHandler,
     event::{StartCause, WindowEvent},
-    event_loop::{ActiveEventLoop, EventLoop},
+    event_loop::{ActiveEventLoop, ControlFlow, EventLoop},
     window::WindowId,
 };
 
 use super::{AppEvent, Task};
+use crate::probe::NetState;
 use crate::utils::{get_program_folder, is_autostart, toggle_autostart};
 
+/// How often to look for reports from the login task.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
 pub struct TrayTask;
 
 impl TrayTask {
@@ -26,10 +31,10 @@ impl TrayTask {
 }
 
 impl Task for TrayTask {
-    fn run(&self, sender: Sender<AppEvent>, _: Receiver<AppEvent>) -> anyhow::Result<()> {
+    fn run(&self, sender: Sender<AppEvent>, receiver: Receiver<AppEvent>) -> anyhow::Result<()> {
         log::debug!("Tray task started.");
 
-        let mut tray = Tray::new(sender);
+        let mut tray = Tray::new(sender, receiver);
         let event_loop = EventLoop::new()?;
         event_loop.run_app(&mut tray)?;
 
@@ -40,18 +45,24 @@ impl Task for TrayTask {
 }
 
 enum UserEvent {
+    LoginNow,
+    Pause,
+    ReloadConfig,
     OpenFolder,
     Autostart,
     Quit,
 }
 
 struct TrayItems {
+    pause: CheckMenuItem,
     autostart: CheckMenuItem,
 }
 
 pub struct Tray {
     menu_channel: &'static MenuEventReceiver,
     sender: Sender<AppEvent>,
+    receiver: Receiver<AppEvent>,
+    paused: bool,
 
     tray_icon: Option<TrayIcon>,
     roi_items: Option<TrayItems>,
@@ -59,10 +70,12 @@ pub struct Tray {
 }
 
 impl Tray {
-    fn new(sender: Sender<AppEvent>) -> Self {
+    fn new(sender: Sender<AppEvent>, receiver: Receiver<AppEvent>) -> Self {
         Tray {
             menu_channel: MenuEvent::receiver(),
             sender,
+            receiver,
+            paused: false,
             tray_icon: None,
             roi_items: None,
             event_table: None,
@@ -74,11 +87,18 @@ impl Tray {
         let autostart = is_autostart();
 
         let menu = Box::new(Menu::new());
+        let menu_item_login = MenuItem::new("Login now", true, None);
+        let menu_item_pause = CheckMenuItem::new("Pause", true, false, None);
+        let menu_item_reload = MenuItem::new("Reload config", true, None);
         let menu_item_openfolder = MenuItem::new("Open folder", true, None);
         let menu_item_autostart = CheckMenuItem::new("Autostart", true, autostart, None);
         let menu_item_quit = MenuItem::new("Quit", true, None);
 
         menu.append_items(&[
+            &menu_item_login,
+            &menu_item_pause,
+            &menu_item_reload,
+            &PredefinedMenuItem::separator(),
             &menu_item_openfolder,
             &menu_item_autostart,
             &PredefinedMenuItem::separator(),
@@ -86,18 +106,22 @@ impl Tray {
         ])?;
 
         let mut event_table = HashMap::new();
+        event_table.insert(menu_item_login.id().to_owned(), UserEvent::LoginNow);
+        event_table.insert(menu_item_pause.id().to_owned(), UserEvent::Pause);
+        event_table.insert(menu_item_reload.id().to_owned(), UserEvent::ReloadConfig);
         event_table.insert(menu_item_openfolder.id().to_owned(), UserEvent::OpenFolder);
         event_table.insert(menu_item_autostart.id().to_owned(), UserEvent::Autostart);
         event_table.insert(menu_item_quit.id().to_owned(), UserEvent::Quit);
 
         let tray_icon = TrayIconBuilder::new()
             .with_menu(menu)
-            .with_tooltip("Tray Icon App Tooltip")
+            .with_tooltip("xdwlan-login")
             .with_icon(icon)
-            .with_title("Tray Icon App")
+            .with_title("xdwlan-login")
             .build()?;
 
         self.roi_items = Some(TrayItems {
+            pause: menu_item_pause,
             autostart: menu_item_autostart,
         });
         self.event_table = Some(event_table);
@@ -105,6 +129,31 @@ impl Tray {
 
         Ok(())
     }
+
+    /// Show what the login task is doing in the tooltip.
+    fn handle_app_event(&mut self, event: AppEvent) {
+        let text = match event {
+            AppEvent::StatusChanged(NetState::Online) => "online".to_string(),
+            AppEvent::StatusChanged(NetState::CaptivePortal) => "offline".to_string(),
+            AppEvent::StatusChanged(NetState::NoNetwork) => "no network".to_string(),
+            AppEvent::LoginAttempt => "logging in...".to_string(),
+            AppEvent::LoginSucceeded => "online".to_string(),
+            AppEvent::LoginFailed(kind) => format!("login failed, {}", kind),
+            _ => return,
+        };
+
+        if let Some(tray_icon) = &self.tray_icon {
+            if let Err(e) = tray_icon.set_tooltip(Some(format!("xdwlan-login: {}", text))) {
+                log::debug!("Failed to set tooltip: {}", e);
+            }
+        }
+    }
+
+    fn send(&self, event: AppEvent) {
+        if let Err(e) = self.sender.send(event) {
+            log::error!("{}", e);
+        }
+    }
 }
 
 impl ApplicationHandler for Tray {
@@ -113,6 +162,12 @@ impl ApplicationHandler for Tray {
     fn window_event(&mut self, _: &ActiveEventLoop, _: WindowId, _: WindowEvent) {}
 
     fn new_events(&mut self, event_loop: &ActiveEventLoop, cause: StartCause) {
+        // Wake up regularly for reports from the login task, which do not wake the event loop.
+        event_loop.set_control_flow(ControlFlow::wait_duration(POLL_INTERVAL));
+        while let Ok(event) = self.receiver.try_recv() {
+            self.handle_app_event(event);
+        }
+
         match cause {
             StartCause::Init => {
                 if let Err(e) = self.init() {
@@ -127,6 +182,26 @@ impl ApplicationHandler for Tray {
                         self.event_table.as_ref().unwrap().get(menu_event.id())
                     {
                         match user_event {
+                            UserEvent::LoginNow => self.send(AppEvent::ForceLogin),
+                            UserEvent::Pause => {
+                                self.paused = !self.paused;
+                                self.roi_items
+                                    .as_mut()
+                                    .unwrap()
+                                    .pause
+                                    .set_checked(self.paused);
+                                self.send(if self.paused {
+                                    AppEvent::Pause
+                                } else {
+                                    AppEvent::Resume
+                                });
+                            }
+                            UserEvent::ReloadConfig => {
+                                // The new login task starts unpaused.
+                                self.paused = false;
+                                self.roi_items.as_mut().unwrap().pause.set_checked(false);
+                                self.send(AppEvent::ReloadConfig);
+                            }
                             UserEvent::OpenFolder => {
                                 // Open the folder where the program is located.
                                 if let Err(e) = std::process::Command::new("explorer")
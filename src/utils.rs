@@ -16,6 +16,82 @@ pub fn get_program_folder() -> String {
         .to_string()
 }
 
+/// Format a byte count with binary units, e.g. `1.50 GiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
+
+/// Format seconds as e.g. `3h 25m 10s`.
+pub fn format_duration(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}h {}m {}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Serve HTTP on a random port for tests, one request per connection. `respond` is given the port, so that responses can point to absolute URLs, and returns a handler that turns the request target, e.g. `/path?query`, into a raw HTTP response. Returns the port.
+#[cfg(test)]
+pub(crate) fn serve_http<F>(respond: impl FnOnce(u16) -> F) -> u16
+where
+    F: FnMut(&str) -> String + Send + 'static,
+{
+    use std::io::{BufRead, BufReader, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut respond = respond(port);
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+            }
+
+            let target = request_line.split_whitespace().nth(1).unwrap();
+            stream.write_all(respond(target).as_bytes()).unwrap();
+        }
+    });
+
+    port
+}
+
+/// A raw HTTP response for `serve_http`.
+#[cfg(test)]
+pub(crate) fn http_response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
 #[cfg(target_os = "windows")]
 const REG_KEY_NAME: &str = "Xidian WLAN Login";
 